@@ -0,0 +1,170 @@
+// Folder-level access control driven by what's in Drive. A folder holding an
+// `.access` file only answers requests that carry one of the credentials it
+// lists, and the same goes for everything below it. Folders without one are
+// as open as the rest of the worker. Looking for the files costs a Drive call
+// per folder along each request's path, so it's off unless FOLDER_ACCESS is
+// set; `.access` files are never served either way.
+//
+// Each line of the file that isn't blank or a `#` comment is one credential:
+// either `user:password`, matched against Basic auth, or a token, matched
+// against the `X-Access-Token` header.
+//
+// When `AUTH_USER` puts the whole site behind Basic auth, the Authorization
+// header already carries the site's credentials and can't carry a folder's
+// as well, so folder credentials are then only taken from `X-Access-Token`,
+// which accepts `user:password` lines too.
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::auth::{base64_decode, constant_time_eq, BasicAuth};
+use crate::config::Config;
+use crate::drive::{DriveClient, UpstreamError};
+use crate::{build_query, cached_json, put_json};
+
+/// Name of the file that makes a folder private. It's never listed or served.
+pub const ACCESS_FILE_NAME: &str = ".access";
+
+// Parsed files are only cached briefly, so edits in Drive take effect soon.
+// KV won't expire anything sooner than a minute.
+const ACCESS_TTL: u64 = 60;
+
+// What a folder's `.access` file says, or None when the folder doesn't have one
+#[derive(Deserialize, Serialize)]
+struct CachedAccess {
+    credentials: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct AccessFiles {
+    files: Vec<AccessFile>,
+}
+
+#[derive(Deserialize)]
+struct AccessFile {
+    id: String,
+}
+
+/// Checks a request against the `.access` files of `folder_ids`, the chain
+/// of folders leading to what was asked for. None means it may go ahead;
+/// otherwise it's the response to send instead, a Basic auth challenge when
+/// a credential is missing or wrong. Behind site-wide auth the browser has
+/// nothing more to offer, so that's a plain 403.
+pub async fn check(auth: &impl DriveClient, config: &Config, folder_ids: &[String], req: &Request) -> Result<Option<Response>> {
+    for folder_id in folder_ids {
+        match is_allowed(auth, config, folder_id, req).await {
+            Ok(true) => {}
+            Ok(false) if config.site_basic_auth => return Ok(Some(Response::error("Forbidden", 403)?)),
+            Ok(false) => return Ok(Some(BasicAuth::challenge()?)),
+            // Failing closed, since an unreadable file could be hiding anything
+            Err(err) => {
+                console_error!("Failed to read {} in {}: {}", ACCESS_FILE_NAME, folder_id, err);
+                return Ok(Some(err.into_response("Failed to check folder access")?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a request may see the contents of a single folder.
+pub async fn is_allowed(auth: &impl DriveClient, config: &Config, folder_id: &str, req: &Request) -> std::result::Result<bool, UpstreamError> {
+    let Some(allowed) = folder_credentials(auth, config, folder_id).await? else {
+        return Ok(true);
+    };
+    // Responses for a private folder mustn't end up in shared caches
+    config.log.record_protected();
+    let offered = offered_credentials(config, req)?;
+    // Every pair is compared, so timing doesn't reveal which entry matched
+    let mut matched = false;
+    for allowed in &allowed {
+        for offered in &offered {
+            matched |= constant_time_eq(allowed.as_bytes(), offered.as_bytes());
+        }
+    }
+    Ok(matched)
+}
+
+/// Whether a folder has an `.access` file at all, whatever the request
+/// offers. Used where output is shared between requesters, like the manifest.
+pub async fn is_protected(auth: &impl DriveClient, config: &Config, folder_id: &str) -> std::result::Result<bool, UpstreamError> {
+    Ok(folder_credentials(auth, config, folder_id).await?.is_some())
+}
+
+// The credentials listed in a folder's `.access` file, from KV when a recent
+// read is cached there. None means the folder has no such file, or that
+// FOLDER_ACCESS is off.
+async fn folder_credentials(auth: &impl DriveClient, config: &Config, folder_id: &str) -> std::result::Result<Option<Vec<String>>, UpstreamError> {
+    if !config.folder_access {
+        return Ok(None);
+    }
+    let cache_key = format!("access:{}", folder_id);
+    if let Some(cached) = cached_json::<CachedAccess>(config, &cache_key).await {
+        return Ok(cached.credentials);
+    }
+
+    let query = build_query(config, folder_id, &[("name =", ACCESS_FILE_NAME)]);
+    let listed: AccessFiles = auth.list(config, &format!("{}&fields=files(id)", query)).await?;
+    let credentials = match listed.files.first() {
+        Some(file) => {
+            let mut response = auth.download(config, &file.id, None, Headers::new(), None).await?;
+            Some(parse(&response.text().await?))
+        }
+        None => None,
+    };
+
+    // Written before answering rather than in the background, which only
+    // costs anything on a miss and keeps the context out of path resolution
+    if let Some(kv) = &config.kv {
+        match serde_json::to_string(&CachedAccess { credentials: credentials.clone() }) {
+            Ok(json) => put_json(kv.clone(), cache_key, json, ACCESS_TTL).await,
+            Err(err) => console_warn!("Failed to serialize access for {}: {}", folder_id, err),
+        }
+    }
+    Ok(credentials)
+}
+
+fn parse(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+// Whatever the request offers to match against an `.access` file: the
+// decoded `user:password` of Basic auth, unless that belongs to site-wide
+// auth, and the `X-Access-Token` header
+fn offered_credentials(config: &Config, req: &Request) -> Result<Vec<String>> {
+    let mut offered = Vec::new();
+    let authorization = if config.site_basic_auth { None } else { req.headers().get("Authorization")? };
+    if let Some(header) = authorization {
+        if let Some((scheme, encoded)) = header.split_once(' ') {
+            if scheme.eq_ignore_ascii_case("Basic") {
+                if let Some(decoded) = base64_decode(encoded.trim()).and_then(|bytes| String::from_utf8(bytes).ok()) {
+                    offered.push(decoded);
+                }
+            }
+        }
+    }
+    if let Some(token) = req.headers().get("X-Access-Token")? {
+        offered.push(token.trim().to_string());
+    }
+    Ok(offered)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::drive::fake::{block_on, FakeDrive};
+
+    #[test]
+    fn ignores_access_files_unless_folder_access_is_on() {
+        let drive = FakeDrive::default().with_file("root", json!({ "id": "a", "name": ACCESS_FILE_NAME, "mimeType": "text/plain" }));
+        let config = Config::default();
+        let protected = block_on(is_protected(&drive, &config, "root")).unwrap_or_else(|err| panic!("{}", err));
+        assert!(!protected);
+    }
+}
@@ -0,0 +1,374 @@
+use std::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+use crate::config::Config;
+
+const DRIVE_SCOPE: &str = "https://www.googleapis.com/auth/drive.readonly";
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+// Lifetime we ask for when minting the signed assertion (Google's maximum)
+const ASSERTION_LIFETIME_SECS: u64 = 3600;
+
+// Access tokens are treated as expired this long before Google says they are,
+// so a request never goes out with a token that dies in flight
+const TOKEN_EXPIRY_MARGIN_SECS: u64 = 300;
+
+// KV rejects expirations shorter than a minute
+const MIN_KV_TTL: u64 = 60;
+
+/// How requests to the Drive API are authenticated.
+#[derive(Clone)]
+pub enum DriveAuth {
+    /// A plain API key, which only works for publicly shared files
+    ApiKey(String),
+    /// An OAuth access token minted for a service account
+    Bearer(String),
+}
+
+impl DriveAuth {
+    /// Prefers a service account when `GOOGLE_SERVICE_ACCOUNT_KEY` is set,
+    /// falling back to `GOOGLE_API_KEY` otherwise. With `IMPERSONATE_USER`
+    /// set, the service account acts as that user through domain-wide
+    /// delegation. With `TOKEN_KV_CACHE` the minted token is shared with
+    /// other isolates through KV.
+    pub async fn from_env(env: &Env, config: &Config) -> Result<Self> {
+        let subject = env
+            .var("IMPERSONATE_USER")
+            .map(|user| user.to_string().trim().to_string())
+            .ok()
+            .filter(|user| !user.is_empty());
+        match env.secret("GOOGLE_SERVICE_ACCOUNT_KEY") {
+            Ok(key_json) => {
+                let key: ServiceAccountKey = serde_json::from_str(&key_json.to_string())
+                    .map_err(|err| Error::from(format!("Invalid service account key: {}", err)))?;
+                let kv = config.kv.as_ref().filter(|_| config.token_kv_cache);
+                Ok(DriveAuth::Bearer(access_token(&key, subject.as_deref(), kv).await?))
+            }
+            Err(_) => {
+                // An API key can't act on anyone's behalf
+                if subject.is_some() {
+                    console_warn!("Ignoring IMPERSONATE_USER, which needs GOOGLE_SERVICE_ACCOUNT_KEY");
+                }
+                Ok(DriveAuth::ApiKey(env.secret("GOOGLE_API_KEY")?.to_string()))
+            }
+        }
+    }
+
+    /// Builds a GET request for a Drive API URL with the credentials attached.
+    /// Every Drive URL we construct already has a query string, so the API key
+    /// can simply be appended.
+    pub fn request(&self, url: &str, headers: Headers) -> Result<Request> {
+        let url = match self {
+            DriveAuth::ApiKey(api_key) => format!("{}&key={}", url, api_key),
+            DriveAuth::Bearer(token) => {
+                headers.set("Authorization", &format!("Bearer {}", token))?;
+                url.to_string()
+            }
+        };
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Get).with_headers(headers);
+        Request::new_with_init(&url, &init)
+    }
+}
+
+/// Credentials required of every client when `AUTH_USER` and `AUTH_PASS` are set.
+pub struct BasicAuth {
+    user: String,
+    pass: String,
+}
+
+impl BasicAuth {
+    /// Returns None when either secret is missing, leaving the worker open.
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let user = env.secret("AUTH_USER").ok()?.to_string();
+        let pass = env.secret("AUTH_PASS").ok()?.to_string();
+        Some(BasicAuth { user, pass })
+    }
+
+    /// Whether the request's Authorization header carries the configured credentials.
+    pub fn is_authorized(&self, req: &Request) -> Result<bool> {
+        let header = match req.headers().get("Authorization")? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        let encoded = match header.split_once(' ') {
+            Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("Basic") => encoded.trim(),
+            _ => return Ok(false),
+        };
+        let decoded = match base64_decode(encoded).and_then(|bytes| String::from_utf8(bytes).ok()) {
+            Some(decoded) => decoded,
+            None => return Ok(false),
+        };
+        let (user, pass) = decoded.split_once(':').unwrap_or((&decoded, ""));
+
+        // Compare both halves even when the first fails so timing doesn't
+        // reveal which one was wrong
+        let user_matches = constant_time_eq(user.as_bytes(), self.user.as_bytes());
+        let pass_matches = constant_time_eq(pass.as_bytes(), self.pass.as_bytes());
+        Ok(user_matches & pass_matches)
+    }
+
+    /// The 401 that prompts browsers for credentials.
+    pub fn challenge() -> Result<Response> {
+        let headers = Headers::new();
+        headers.set("WWW-Authenticate", "Basic realm=\"files\", charset=\"UTF-8\"")?;
+        Ok(Response::error("Unauthorized", 401)?.with_headers(headers))
+    }
+}
+
+/// Whether a request carries the `PURGE_SECRET` in its `X-Purge-Secret`
+/// header. Without the secret configured, nobody is.
+pub fn is_purge_authorized(env: &Env, req: &Request) -> Result<bool> {
+    let secret = match env.secret("PURGE_SECRET") {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Ok(false),
+    };
+    match req.headers().get("X-Purge-Secret")? {
+        Some(given) if !secret.is_empty() => Ok(constant_time_eq(given.as_bytes(), secret.as_bytes())),
+        _ => Ok(false),
+    }
+}
+
+// Compares two byte strings in time that depends only on their lengths
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut difference = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        difference |= (x ^ y) as usize;
+    }
+    difference == 0
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    // The user being impersonated, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<&'a str>,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    client_email: String,
+    subject: Option<String>,
+    access_token: String,
+    expires_at: u64,
+}
+
+thread_local! {
+    // Isolates are single-threaded and reused across requests, so this lets
+    // warm requests skip the token exchange entirely
+    static TOKEN_CACHE: RefCell<Option<CachedToken>> = const { RefCell::new(None) };
+}
+
+fn now_secs() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+impl CachedToken {
+    fn is_usable(&self, key: &ServiceAccountKey, subject: Option<&str>, now: u64) -> bool {
+        self.client_email == key.client_email
+            && self.subject.as_deref() == subject
+            && self.expires_at > now + TOKEN_EXPIRY_MARGIN_SECS
+    }
+}
+
+// Where a token for this account and subject is shared in KV
+fn token_cache_key(key: &ServiceAccountKey, subject: Option<&str>) -> String {
+    format!("token:{}:{}", key.client_email, subject.unwrap_or_default())
+}
+
+// Hands out a token from memory, then from KV when `kv` is given, and only
+// mints a new one when neither has one with time left on it. Isolates that
+// miss at the same moment each mint and store their own; any of them will
+// do, so the last write simply wins.
+async fn access_token(key: &ServiceAccountKey, subject: Option<&str>, kv: Option<&KvStore>) -> Result<String> {
+    let now = now_secs();
+    let cached = TOKEN_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .as_ref()
+            .filter(|token| token.is_usable(key, subject, now))
+            .map(|token| token.access_token.clone())
+    });
+    if let Some(token) = cached {
+        return Ok(token);
+    }
+
+    if let Some(kv) = kv {
+        match kv.get(&token_cache_key(key, subject)).json::<CachedToken>().await {
+            Ok(Some(token)) if token.is_usable(key, subject, now) => {
+                let access_token = token.access_token.clone();
+                TOKEN_CACHE.with(|cache| *cache.borrow_mut() = Some(token));
+                return Ok(access_token);
+            }
+            Ok(_) => {}
+            // Minting is the fallback either way
+            Err(err) => console_warn!("Failed to read shared access token: {:?}", err),
+        }
+    }
+
+    console_debug!("Minting access token for {}", subject.unwrap_or(&key.client_email));
+    let token_uri = key.token_uri.as_deref().unwrap_or(DEFAULT_TOKEN_URI);
+    let assertion = signed_assertion(key, subject, token_uri, now).await?;
+
+    let body = format!(
+        "grant_type={}&assertion={}",
+        urlencoding::encode("urn:ietf:params:oauth:grant-type:jwt-bearer"),
+        assertion
+    );
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.into()));
+
+    let request = Request::new_with_init(token_uri, &init)?;
+    let mut response = Fetch::Request(request).send().await?;
+
+    let status_code = response.status_code();
+    if !(200..300).contains(&status_code) {
+        let message = response.text().await.unwrap_or_default();
+        return Err(Error::from(format!("Token exchange failed ({}): {}", status_code, message)));
+    }
+
+    let token: TokenResponse = response.json().await?;
+    let cached = CachedToken {
+        client_email: key.client_email.clone(),
+        subject: subject.map(str::to_string),
+        access_token: token.access_token.clone(),
+        expires_at: now + token.expires_in,
+    };
+
+    // Kept in KV only for as long as other isolates could still use it
+    if let Some(kv) = kv {
+        let ttl = token.expires_in.saturating_sub(TOKEN_EXPIRY_MARGIN_SECS).max(MIN_KV_TTL);
+        let result = match kv.put(&token_cache_key(key, subject), &cached) {
+            Ok(put) => put.expiration_ttl(ttl).execute().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            console_warn!("Failed to share access token: {:?}", err);
+        }
+    }
+
+    TOKEN_CACHE.with(|cache| *cache.borrow_mut() = Some(cached));
+    Ok(token.access_token)
+}
+
+// Builds the RS256-signed JWT that Google exchanges for an access token
+async fn signed_assertion(key: &ServiceAccountKey, subject: Option<&str>, token_uri: &str, now: u64) -> Result<String> {
+    let header = base64_url_encode(br#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = Claims {
+        iss: &key.client_email,
+        sub: subject,
+        scope: DRIVE_SCOPE,
+        aud: token_uri,
+        iat: now,
+        exp: now + ASSERTION_LIFETIME_SECS,
+    };
+    let claims = base64_url_encode(serde_json::to_string(&claims)?.as_bytes());
+    let signing_input = format!("{}.{}", header, claims);
+
+    let signature = rsa_sha256_sign(&key.private_key, signing_input.as_bytes()).await?;
+    Ok(format!("{}.{}", signing_input, base64_url_encode(&signature)))
+}
+
+async fn rsa_sha256_sign(private_key_pem: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let der = pem_to_der(private_key_pem)
+        .ok_or_else(|| Error::from("Service account private key is not valid PEM"))?;
+
+    let crypto: web_sys::Crypto = js_sys::Reflect::get(&js_sys::global(), &"crypto".into())?.unchecked_into();
+    let subtle = crypto.subtle();
+
+    let algorithm = js_sys::Object::new();
+    js_sys::Reflect::set(&algorithm, &"name".into(), &"RSASSA-PKCS1-v1_5".into())?;
+    js_sys::Reflect::set(&algorithm, &"hash".into(), &"SHA-256".into())?;
+    let usages = js_sys::Array::of1(&"sign".into());
+
+    let key_data = js_sys::Uint8Array::from(der.as_slice());
+    let crypto_key = JsFuture::from(subtle.import_key_with_object("pkcs8", &key_data, &algorithm, false, &usages)?)
+        .await?
+        .unchecked_into::<web_sys::CryptoKey>();
+
+    let signature = JsFuture::from(subtle.sign_with_object_and_u8_array(&algorithm, &crypto_key, data)?).await?;
+    Ok(js_sys::Uint8Array::new(&signature).to_vec())
+}
+
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<Vec<_>>()
+        .join("");
+    base64_decode(body.trim())
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_url_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let triple = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..=chunk.len() {
+            let index = (triple >> (18 - 6 * i)) & 0x3f;
+            let c = match BASE64_ALPHABET[index as usize] {
+                b'+' => '-',
+                b'/' => '_',
+                c => c as char,
+            };
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+pub(crate) fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            b'\r' | b'\n' | b' ' => continue,
+            _ => return None,
+        };
+        buffer = buffer << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
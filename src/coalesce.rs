@@ -0,0 +1,386 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+use futures_util::stream::{self, LocalBoxStream, Stream, StreamExt};
+use worker::*;
+
+use crate::auth::DriveAuth;
+use crate::config::Config;
+use crate::drive::{DriveClient, UpstreamError};
+
+/// Largest file (in bytes) whose download is shared. A shared download is
+/// held in the object's memory until the last request reading it is done, so
+/// anything bigger, or of unknown size like an export, is downloaded by each
+/// request on its own.
+pub const MAX_COALESCED_BYTES: u64 = 32 * 1024 * 1024;
+
+// Where requests are sent on the object's stub; only the query string matters
+const COALESCER_URL: &str = "https://coalescer/";
+
+/// Downloads a file through the Durable Object named after it, so that
+/// concurrent cache misses for the same file share a single transfer from
+/// Drive. A burst of N requests for an uncached file costs one download
+/// instead of N.
+///
+/// The body streams in as Drive sends it, and a failure comes back with the
+/// status and message Drive's would have had. Once `signal` fires this
+/// request stops reading, and when every request has, the object abandons
+/// the transfer from Drive too.
+pub async fn download(coalescer: &ObjectNamespace, file_id: &str, signal: &AbortSignal) -> std::result::Result<Response, UpstreamError> {
+    let url = format!("{}?id={}", COALESCER_URL, urlencoding::encode(file_id));
+    // A stub only takes a signal as part of the request
+    let init = web_sys::RequestInit::new();
+    init.set_signal(Some(signal));
+    let request = web_sys::Request::new_with_str_and_init(&url, &init).map_err(Error::from)?;
+    let stub = coalescer.id_from_name(file_id)?.get_stub()?;
+    let mut response = match stub.fetch_with_request(Request::from(request)).await {
+        Err(_) if signal.aborted() => return Err(UpstreamError::Cancelled),
+        response => response?,
+    };
+    let status = response.status_code();
+    if !(200..300).contains(&status) {
+        return Err(UpstreamError::Status {
+            status,
+            message: response.text().await?,
+        });
+    }
+    Ok(response)
+}
+
+// A piece of a download as Drive sent it, or the status and message the
+// download failed with
+type Chunk = std::result::Result<Rc<[u8]>, (u16, String)>;
+
+// A download from Drive, yet to be shared
+type Upstream = LocalBoxStream<'static, std::result::Result<Vec<u8>, (u16, String)>>;
+
+/// Each object is named after one file, so it only ever has one download in
+/// flight. Requests that arrive while it's running read it rather than
+/// starting their own; the first one in after it finishes starts a fresh
+/// one, since by then the cache should be answering instead.
+#[durable_object(fetch)]
+pub struct DownloadCoalescer {
+    env: Env,
+    flight: RefCell<Weak<SharedDownload>>,
+}
+
+impl DurableObject for DownloadCoalescer {
+    fn new(_state: State, env: Env) -> Self {
+        DownloadCoalescer {
+            env,
+            flight: RefCell::new(Weak::new()),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+        let file_id = match param("id") {
+            Some(file_id) => file_id,
+            None => return Response::error("Missing file ID", 400),
+        };
+
+        let (mut reader, joined) = join(&self.flight, || fetch_once(self.env.clone(), file_id.clone()));
+        if joined {
+            console_log!("Shared the download of {} already in flight", file_id);
+        }
+
+        // The first chunk decides between a streamed body and an error
+        let first = reader.next().await;
+        if let Some(Err((status, message))) = first {
+            return Response::error(message, status);
+        }
+        let body = stream::iter(first).chain(reader).map(|chunk| match chunk {
+            Ok(chunk) => Ok(chunk.to_vec()),
+            Err((_, message)) => Err(Error::RustError(message)),
+        });
+        Response::from_stream(body)
+    }
+}
+
+// Reads the download in flight, or starts one with `start` when there's
+// none, saying which it did
+fn join(flight: &RefCell<Weak<SharedDownload>>, start: impl FnOnce() -> Upstream) -> (Reader, bool) {
+    let mut flight = flight.borrow_mut();
+    if let Some(download) = flight.upgrade().filter(|download| !download.is_finished()) {
+        return (Reader { download, next: 0 }, true);
+    }
+    let download = Rc::new(SharedDownload {
+        upstream: RefCell::new(Some(start())),
+        chunks: RefCell::new(Vec::new()),
+        wakers: Arc::default(),
+    });
+    *flight = Rc::downgrade(&download);
+    (Reader { download, next: 0 }, false)
+}
+
+// Starts the download itself with the object's own credentials
+fn fetch_once(env: Env, file_id: String) -> Upstream {
+    let response = async move {
+        let config = Config::from_env(&env);
+        let auth = DriveAuth::from_env(&env, &config).await?;
+        let mut response = auth.download(&config, &file_id, None, Headers::new(), None).await?;
+        Ok::<ByteStream, UpstreamError>(response.stream()?)
+    };
+    stream::once(response)
+        .flat_map(|response| match response {
+            Ok(body) => body.map(|chunk| chunk.map_err(|err| (502, err.to_string()))).left_stream(),
+            Err(err) => {
+                console_error!("Shared download failed: {}", err);
+                let failure = match err.client_error() {
+                    Ok(client_error) => client_error,
+                    Err(err) => (500, err.to_string()),
+                };
+                stream::iter([Err(failure)]).right_stream()
+            }
+        })
+        .boxed_local()
+}
+
+/// A download read by every request that joined it. Chunks are kept as they
+/// arrive, so a request that joins late replays them before following along.
+/// They're shared rather than copied, so the file is held in memory once
+/// however many requests are reading it, and it's let go along with the last
+/// of them. If they all go away early, so does the transfer from Drive.
+struct SharedDownload {
+    // None once Drive has sent everything, or failed
+    upstream: RefCell<Option<Upstream>>,
+    chunks: RefCell<Vec<Chunk>>,
+    wakers: Arc<Wakers>,
+}
+
+impl SharedDownload {
+    fn is_finished(&self) -> bool {
+        self.upstream.borrow().is_none()
+    }
+}
+
+// Whichever reader is furthest along pulls the next chunk from Drive, and
+// whoever's waiting is woken when it lands, even if that reader has gone
+struct Reader {
+    download: Rc<SharedDownload>,
+    next: usize,
+}
+
+impl Stream for Reader {
+    type Item = Chunk;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Chunk>> {
+        let this = self.get_mut();
+        let download = &*this.download;
+        loop {
+            let chunk = download.chunks.borrow().get(this.next).cloned();
+            if let Some(chunk) = chunk {
+                this.next += 1;
+                return Poll::Ready(Some(chunk));
+            }
+
+            let mut upstream = download.upstream.borrow_mut();
+            let Some(body) = upstream.as_mut() else {
+                return Poll::Ready(None);
+            };
+            download.wakers.register(cx.waker());
+            let waker = Waker::from(download.wakers.clone());
+            match body.poll_next_unpin(&mut Context::from_waker(&waker)) {
+                Poll::Ready(Some(chunk)) => {
+                    if chunk.is_err() {
+                        *upstream = None;
+                    }
+                    download.chunks.borrow_mut().push(chunk.map(Rc::from));
+                }
+                Poll::Ready(None) => *upstream = None,
+                Poll::Pending => return Poll::Pending,
+            }
+            download.wakers.wake_all();
+        }
+    }
+}
+
+// The readers waiting on the next chunk
+#[derive(Default)]
+struct Wakers(Mutex<Vec<Waker>>);
+
+impl Wakers {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&self) {
+        let wakers = std::mem::take(&mut *self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    const CHUNKS: usize = 8;
+
+    // Drive's end of a download, releasing chunks when the test says so
+    #[derive(Default)]
+    struct Source {
+        released: VecDeque<Vec<u8>>,
+        sent: usize,
+        waker: Option<Waker>,
+    }
+
+    impl Source {
+        fn release(source: &Rc<RefCell<Source>>, chunks: usize) {
+            let mut source = source.borrow_mut();
+            for _ in 0..chunks {
+                source.released.push_back(vec![0; CHUNK_SIZE]);
+            }
+            if let Some(waker) = source.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn upstream(source: Rc<RefCell<Source>>) -> Upstream {
+        stream::poll_fn(move |cx| {
+            let mut source = source.borrow_mut();
+            if let Some(chunk) = source.released.pop_front() {
+                source.sent += 1;
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+            if source.sent == CHUNKS {
+                return Poll::Ready(None);
+            }
+            source.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .boxed_local()
+    }
+
+    // Reads whatever's available without waiting, returning the chunks and
+    // whether the download is over
+    fn read_available(reader: &mut Reader) -> (Vec<Rc<[u8]>>, bool) {
+        let mut chunks = Vec::new();
+        loop {
+            match reader.poll_next_unpin(&mut Context::from_waker(Waker::noop())) {
+                Poll::Ready(Some(chunk)) => chunks.push(chunk.unwrap_or_else(|(status, message)| panic!("{}: {}", status, message))),
+                Poll::Ready(None) => return (chunks, true),
+                Poll::Pending => return (chunks, false),
+            }
+        }
+    }
+
+    #[test]
+    fn shares_one_download_between_overlapping_requests() {
+        const REQUESTS: usize = 10;
+        let flight = RefCell::new(Weak::new());
+        let source = Rc::new(RefCell::new(Source::default()));
+        let downloads = Cell::new(0);
+        let start = || {
+            downloads.set(downloads.get() + 1);
+            upstream(source.clone())
+        };
+
+        // Requests keep arriving while the download is under way, some
+        // before the first chunk and some near the end
+        let mut readers = Vec::new();
+        let mut received = Vec::new();
+        for request in 0..REQUESTS {
+            readers.push(join(&flight, start).0);
+            received.push(Vec::new());
+            if request % 3 == 2 {
+                Source::release(&source, 2);
+            }
+            for (reader, received) in readers.iter_mut().zip(&mut received) {
+                received.extend(read_available(reader).0);
+            }
+        }
+        let unreleased = CHUNKS - source.borrow().sent - source.borrow().released.len();
+        Source::release(&source, unreleased);
+        for (reader, received) in readers.iter_mut().zip(&mut received) {
+            let (chunks, finished) = read_available(reader);
+            received.extend(chunks);
+            assert!(finished);
+        }
+
+        assert_eq!(downloads.get(), 1);
+        // Every request read the one copy the object holds
+        for received in &received {
+            assert_eq!(received.len(), CHUNKS);
+            assert!(received.iter().zip(&readers[0].download.chunks.borrow()[..]).all(|(chunk, held)| Rc::ptr_eq(chunk, held.as_ref().unwrap())));
+        }
+    }
+
+    #[test]
+    fn downloads_again_for_requests_that_arrive_after_it_finished() {
+        const REQUESTS: usize = 10;
+        let flight = RefCell::new(Weak::new());
+        let downloads = Cell::new(0);
+        for _ in 0..REQUESTS {
+            let source = Rc::new(RefCell::new(Source::default()));
+            let (mut reader, _) = join(&flight, || {
+                downloads.set(downloads.get() + 1);
+                upstream(source.clone())
+            });
+            Source::release(&source, CHUNKS);
+            let (chunks, finished) = read_available(&mut reader);
+            assert_eq!(chunks.len(), CHUNKS);
+            assert!(finished);
+        }
+        assert_eq!(downloads.get(), REQUESTS);
+    }
+
+    #[test]
+    fn wakes_waiting_requests_when_the_one_pulling_from_drive_has_gone() {
+        struct Woken(std::sync::atomic::AtomicUsize);
+        impl Wake for Woken {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        let flight = RefCell::new(Weak::new());
+        let source = Rc::new(RefCell::new(Source::default()));
+        let (mut waiting, _) = join(&flight, || upstream(source.clone()));
+        let woken = Arc::new(Woken(Default::default()));
+        let waker = Waker::from(woken.clone());
+        assert!(waiting.poll_next_unpin(&mut Context::from_waker(&waker)).is_pending());
+
+        // The last to poll is the one Drive's stream will wake
+        let (mut pulling, _) = join(&flight, || unreachable!());
+        assert!(read_available(&mut pulling).0.is_empty());
+        drop(pulling);
+
+        Source::release(&source, 1);
+        assert_eq!(woken.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(read_available(&mut waiting).0.len(), 1);
+    }
+
+    #[test]
+    fn abandons_the_drive_download_once_every_request_has_gone() {
+        let flight = RefCell::new(Weak::new());
+        let source = Rc::new(RefCell::new(Source::default()));
+        let (mut first, _) = join(&flight, || upstream(source.clone()));
+        let (second, _) = join(&flight, || unreachable!());
+        Source::release(&source, 1);
+        assert_eq!(read_available(&mut first).0.len(), 1);
+
+        // Only the test and the download itself hold on to Drive's end
+        drop(first);
+        assert_eq!(Rc::strong_count(&source), 2);
+        drop(second);
+        assert_eq!(Rc::strong_count(&source), 1);
+    }
+}
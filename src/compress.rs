@@ -0,0 +1,68 @@
+use worker::*;
+
+/// Picks the encoding to compress a response with, if any. Only text-like
+/// types are worth compressing, and bodies known to be smaller than
+/// `min_bytes` are sent as-is.
+pub fn negotiate(req: &Request, content_type: &str, body_len: Option<u64>, min_bytes: u64) -> Result<Option<&'static str>> {
+    if !is_compressible(content_type) || body_len.is_some_and(|len| len < min_bytes) {
+        return Ok(None);
+    }
+    let accept_encoding = req.headers().get("Accept-Encoding")?.unwrap_or_default().to_ascii_lowercase();
+    let accepts = |encoding: &str| {
+        accept_encoding
+            .split(',')
+            .map(|token| token.trim())
+            .any(|token| token == encoding || (token.starts_with(&format!("{};", encoding)) && !token.ends_with("q=0")))
+    };
+    let encoding = if accepts("br") {
+        Some("br")
+    } else if accepts("gzip") {
+        Some("gzip")
+    } else {
+        None
+    };
+    Ok(encoding)
+}
+
+/// Marks a response for compression when the client and content type allow it.
+pub fn apply(req: &Request, response: Response, content_type: &str, body_len: Option<u64>, min_bytes: u64) -> Result<Response> {
+    match negotiate(req, content_type, body_len, min_bytes)? {
+        Some(encoding) => with_encoding(response, encoding),
+        None => Ok(response),
+    }
+}
+
+/// Marks a response for compression. The Workers runtime compresses the body
+/// itself when it sees a Content-Encoding header, so the body stays untouched
+/// here. Headers are copied because fetched and cached responses have
+/// immutable ones.
+pub fn with_encoding(response: Response, encoding: &str) -> Result<Response> {
+    let headers = Headers::new();
+    for (name, value) in response.headers().entries() {
+        // The compressed length isn't known until the runtime encodes the body
+        if !name.eq_ignore_ascii_case("Content-Length") {
+            headers.append(&name, &value)?;
+        }
+    }
+    headers.set("Content-Encoding", encoding)?;
+    headers.append("Vary", "Accept-Encoding")?;
+    Ok(response.with_headers(headers).with_encode_body(EncodeBody::Automatic))
+}
+
+// Text formats compress well; media and archives are already compressed
+fn is_compressible(content_type: &str) -> bool {
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    mime_type.starts_with("text/")
+        || matches!(
+            mime_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/xhtml+xml"
+                | "application/rss+xml"
+                | "application/atom+xml"
+                | "application/manifest+json"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
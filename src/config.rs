@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+
+use worker::{console_warn, Env, KvStore, ObjectNamespace};
+
+use crate::auth::BasicAuth;
+use crate::log::{LogLevel, RequestLog};
+
+// How long downloaded files stay in the edge cache when CACHE_MAX_AGE isn't set
+const DEFAULT_CACHE_MAX_AGE: u64 = 3600;
+
+// Exports are rendered from documents that can change at any moment, so
+// they're cached briefly unless EXPORT_CACHE_MAX_AGE says otherwise
+const DEFAULT_EXPORT_CACHE_MAX_AGE: u64 = 300;
+
+// How long browsers and the CDN may reuse a generated listing when
+// LISTING_CACHE_MAX_AGE isn't set
+const DEFAULT_LISTING_CACHE_MAX_AGE: u64 = 60;
+
+// How long folder listings stay fresh when LISTING_TTL isn't set
+const DEFAULT_LISTING_TTL: u64 = 300;
+
+// How long past that a listing may still be served while it's refreshed in
+// the background, when LISTING_STALE_TTL isn't set
+const DEFAULT_LISTING_STALE_TTL: u64 = 3600;
+
+// Bounds Drive puts on a files.list page. LIST_PAGE_SIZE is held to them,
+// and without it pages are as large as Drive allows.
+const MIN_LIST_PAGE_SIZE: u32 = 1;
+const MAX_LIST_PAGE_SIZE: u32 = 1000;
+
+// Largest file `?preview=` shows when PREVIEW_MAX_BYTES isn't set
+const DEFAULT_PREVIEW_MAX_BYTES: u64 = 64 * 1024;
+
+// How long a built manifest stays in KV when MANIFEST_TTL isn't set
+const DEFAULT_MANIFEST_TTL: u64 = 3600;
+
+// KV rejects expirations shorter than a minute
+const MIN_KV_TTL: u64 = 60;
+
+// Responses smaller than this aren't worth compressing
+const DEFAULT_COMPRESSION_MIN_BYTES: u64 = 1024;
+
+// Total tries (including the first) for a Drive request that keeps failing transiently
+const DEFAULT_DRIVE_MAX_ATTEMPTS: u32 = 3;
+
+// How long to wait on Drive when DRIVE_TIMEOUT_MS isn't set, leaving headroom
+// under the platform's own limits
+const DEFAULT_DRIVE_TIMEOUT_MS: u64 = 25_000;
+
+// Charset declared for text files when DEFAULT_CHARSET isn't set
+const DEFAULT_CHARSET: &str = "utf-8";
+
+// Length of a rate limiting window when RATE_LIMIT_WINDOW isn't set
+const DEFAULT_RATE_LIMIT_WINDOW: u64 = 60;
+
+/// Tunables read from the worker's environment variables.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Default))]
+pub struct Config {
+    /// `max-age` (in seconds) sent with served files and used for the edge cache
+    pub cache_max_age: u64,
+    /// Per-type overrides of `cache_max_age` from `CACHE_MAX_AGE_BY_TYPE`, a
+    /// JSON object of mime-type prefix to seconds, longest prefix first
+    pub cache_max_age_by_type: Vec<(String, u64)>,
+    /// Forced Content-Types from `MIME_TYPES`, a JSON object keyed by
+    /// extension (`wasm` or `.wasm`) or by full file name, lowercased and
+    /// without a leading dot
+    pub mime_overrides: HashMap<String, String>,
+    /// Default Content-Disposition (`inline` or `attachment`) by mime-type
+    /// prefix from `DISPOSITION_BY_TYPE`, longest prefix first
+    pub disposition_by_type: Vec<(String, String)>,
+    /// `max-age` for Google-native files exported on the fly
+    pub export_cache_max_age: u64,
+    /// `max-age` for generated folder listings
+    pub listing_cache_max_age: u64,
+    /// Listing sort key used without `?sort=`: `name`, `modified` or `size`
+    pub default_sort: Option<String>,
+    /// Listing order used without `?order=`: `asc` or `desc`
+    pub default_order: Option<String>,
+    /// Serve a folder's `index.html` in place of the generated listing
+    pub serve_index_html: bool,
+    /// Give listed files a short `/s/<slug>` link, from `SHORT_LINKS`. The
+    /// slugs are kept in KV, so this does nothing without it.
+    pub short_links: bool,
+    /// Origins allowed to make cross-origin requests, from `ALLOWED_ORIGINS`
+    pub allowed_origins: Vec<String>,
+    /// Total attempts for a Drive request that hits rate limits or 5xx errors
+    pub drive_max_attempts: u32,
+    /// Milliseconds to wait for Drive to start responding before giving up
+    pub drive_timeout_ms: u64,
+    /// Replacement page shell for generated pages, with `{{title}}`,
+    /// `{{content}}` and `{{theme}}` placeholders
+    pub listing_template: Option<String>,
+    /// Replacement markup for each listing entry, with `{{name}}`, `{{link}}`,
+    /// `{{type}}` and `{{size}}` placeholders, plus `{{short_link}}` for the
+    /// markup of the entry's short link, if any
+    pub listing_row_template: Option<String>,
+    /// Charset added to text-like Content-Types that don't carry one
+    pub default_charset: String,
+    /// Smallest body (in bytes) that gets compressed for clients that accept it
+    pub compression_min_bytes: u64,
+    /// Largest text file (in bytes) a listing will preview inline
+    pub preview_max_bytes: u64,
+    /// Buffer downloads to check them against Drive's MD5 before sending
+    /// them, from `VERIFY_CHECKSUMS`
+    pub verify_checksums: bool,
+    /// Share minted access tokens between isolates through KV, from
+    /// `TOKEN_KV_CACHE`
+    pub token_kv_cache: bool,
+    /// KV namespace bound as `FILES_KV`, used for caching when present
+    pub kv: Option<KvStore>,
+    /// Durable Object namespace bound as `DOWNLOAD_COALESCER`, used to share
+    /// downloads between concurrent cache misses when present
+    pub coalescer: Option<ObjectNamespace>,
+    /// Shared drive that list and search queries are scoped to, from the
+    /// `SHARED_DRIVE_ID` secret (or variable)
+    pub shared_drive_id: Option<String>,
+    /// Seconds a cached folder listing is served as is
+    pub listing_ttl: u64,
+    /// Seconds past `listing_ttl` that a cached listing is still served while a
+    /// fresh one is fetched in the background
+    pub listing_stale_ttl: u64,
+    /// Entries asked of Drive per files.list request when fetching a whole
+    /// listing, from `LIST_PAGE_SIZE`
+    pub list_page_size: u32,
+    /// Seconds the recursive file manifest stays cached in KV
+    pub manifest_ttl: u64,
+    /// Requests a single client IP may make per window; unset disables rate
+    /// limiting, as does a missing KV binding
+    pub rate_limit: Option<u32>,
+    /// Length of a rate limiting window in seconds
+    pub rate_limit_window: u64,
+    /// Extensions that may be served, from `ALLOW_EXTENSIONS`; empty allows all
+    pub allow_extensions: Vec<String>,
+    /// Extensions that are never served, from `DENY_EXTENSIONS`
+    pub deny_extensions: Vec<String>,
+    /// Fall back to matching file names by prefix when no name matches
+    /// exactly, from `NAME_MATCH=prefix`
+    pub prefix_name_match: bool,
+    /// Serve the root folder's listing at `/` itself instead of redirecting
+    /// to it, from `ROOT_PATH=listing`
+    pub root_listing: bool,
+    /// Names or IDs of files listed ahead of everything else, in this order,
+    /// from `PINNED_FILES`. Only whole listings are reordered: with `?page=`
+    /// or `?per=` each page comes straight from Drive, so pins stay where the
+    /// sort puts them
+    pub pinned_files: Vec<String>,
+    /// Tell crawlers to stay away when the root folder has no `robots.txt`,
+    /// from `ROBOTS=disallow`
+    pub robots_disallow: bool,
+    /// Honor `.access` files, from `FOLDER_ACCESS`. Every request then looks
+    /// for one in each folder along its path, which is only cached when KV
+    /// is bound
+    pub folder_access: bool,
+    /// Whether `AUTH_USER` and `AUTH_PASS` put the whole site behind Basic
+    /// auth, which then leaves the Authorization header to them
+    pub site_basic_auth: bool,
+    /// Base path the worker is mounted under, from `PATH_PREFIX`, with a
+    /// leading slash and no trailing one; empty when served from the root
+    pub path_prefix: String,
+    /// Headers added to every response from `RESPONSE_HEADERS`, a JSON object
+    /// of header names to values
+    pub response_headers: Vec<(String, String)>,
+    /// Client IPs of proxies in front of the worker, from `TRUSTED_PROXIES`.
+    /// Only their `X-Forwarded-Host` and `X-Forwarded-Proto` are believed.
+    pub trusted_proxies: Vec<String>,
+    /// Keep configuration details out of responses, from `PRODUCTION`
+    pub production: bool,
+    /// Least severe log output that gets written
+    pub log_level: LogLevel,
+    /// What's been learned about the current request, for its log line
+    pub log: RequestLog,
+}
+
+impl Config {
+    pub fn from_env(env: &Env) -> Self {
+        Config {
+            cache_max_age: parsed_var(env, "CACHE_MAX_AGE").unwrap_or(DEFAULT_CACHE_MAX_AGE),
+            cache_max_age_by_type: prefix_map_var(env, "CACHE_MAX_AGE_BY_TYPE"),
+            mime_overrides: mime_map_var(env, "MIME_TYPES"),
+            disposition_by_type: prefix_map_var::<String>(env, "DISPOSITION_BY_TYPE")
+                .into_iter()
+                .filter_map(|(prefix, disposition)| match disposition.to_ascii_lowercase().as_str() {
+                    "inline" | "attachment" => Some((prefix, disposition.to_ascii_lowercase())),
+                    _ => {
+                        console_warn!("Ignoring DISPOSITION_BY_TYPE entry for {}: {}", prefix, disposition);
+                        None
+                    }
+                })
+                .collect(),
+            export_cache_max_age: parsed_var(env, "EXPORT_CACHE_MAX_AGE").unwrap_or(DEFAULT_EXPORT_CACHE_MAX_AGE),
+            listing_cache_max_age: parsed_var(env, "LISTING_CACHE_MAX_AGE").unwrap_or(DEFAULT_LISTING_CACHE_MAX_AGE),
+            default_sort: var(env, "DEFAULT_SORT"),
+            default_order: var(env, "DEFAULT_ORDER"),
+            serve_index_html: flag_var(env, "SERVE_INDEX_HTML").unwrap_or(true),
+            short_links: flag_var(env, "SHORT_LINKS").unwrap_or(false),
+            allowed_origins: list_var(env, "ALLOWED_ORIGINS"),
+            drive_max_attempts: parsed_var(env, "DRIVE_MAX_ATTEMPTS").unwrap_or(DEFAULT_DRIVE_MAX_ATTEMPTS).max(1),
+            drive_timeout_ms: parsed_var(env, "DRIVE_TIMEOUT_MS").unwrap_or(DEFAULT_DRIVE_TIMEOUT_MS),
+            listing_template: var(env, "LISTING_TEMPLATE"),
+            listing_row_template: var(env, "LISTING_ROW_TEMPLATE"),
+            default_charset: var(env, "DEFAULT_CHARSET").unwrap_or_else(|| DEFAULT_CHARSET.to_string()),
+            compression_min_bytes: parsed_var(env, "COMPRESSION_MIN_BYTES").unwrap_or(DEFAULT_COMPRESSION_MIN_BYTES),
+            preview_max_bytes: parsed_var(env, "PREVIEW_MAX_BYTES").unwrap_or(DEFAULT_PREVIEW_MAX_BYTES),
+            verify_checksums: flag_var(env, "VERIFY_CHECKSUMS").unwrap_or(false),
+            token_kv_cache: flag_var(env, "TOKEN_KV_CACHE").unwrap_or(false),
+            kv: env.kv("FILES_KV").ok(),
+            coalescer: env.durable_object("DOWNLOAD_COALESCER").ok(),
+            shared_drive_id: var(env, "SHARED_DRIVE_ID"),
+            listing_ttl: parsed_var(env, "LISTING_TTL").unwrap_or(DEFAULT_LISTING_TTL).max(MIN_KV_TTL),
+            listing_stale_ttl: parsed_var(env, "LISTING_STALE_TTL").unwrap_or(DEFAULT_LISTING_STALE_TTL),
+            list_page_size: parsed_var(env, "LIST_PAGE_SIZE")
+                .unwrap_or(MAX_LIST_PAGE_SIZE)
+                .clamp(MIN_LIST_PAGE_SIZE, MAX_LIST_PAGE_SIZE),
+            manifest_ttl: parsed_var(env, "MANIFEST_TTL").unwrap_or(DEFAULT_MANIFEST_TTL).max(MIN_KV_TTL),
+            rate_limit: parsed_var(env, "RATE_LIMIT"),
+            rate_limit_window: parsed_var(env, "RATE_LIMIT_WINDOW").unwrap_or(DEFAULT_RATE_LIMIT_WINDOW).max(1),
+            allow_extensions: extension_list_var(env, "ALLOW_EXTENSIONS"),
+            deny_extensions: extension_list_var(env, "DENY_EXTENSIONS"),
+            prefix_name_match: var(env, "NAME_MATCH").is_some_and(|mode| mode.eq_ignore_ascii_case("prefix")),
+            root_listing: var(env, "ROOT_PATH").is_some_and(|mode| mode.eq_ignore_ascii_case("listing")),
+            pinned_files: list_var(env, "PINNED_FILES"),
+            robots_disallow: var(env, "ROBOTS").is_some_and(|mode| mode.eq_ignore_ascii_case("disallow")),
+            folder_access: flag_var(env, "FOLDER_ACCESS").unwrap_or(false),
+            site_basic_auth: BasicAuth::from_env(env).is_some(),
+            path_prefix: var(env, "PATH_PREFIX")
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty())
+                .map_or_else(String::new, |prefix| format!("/{}", prefix)),
+            response_headers: header_map_var(env, "RESPONSE_HEADERS"),
+            trusted_proxies: list_var(env, "TRUSTED_PROXIES"),
+            production: flag_var(env, "PRODUCTION").unwrap_or(false),
+            log_level: parsed_var(env, "LOG_LEVEL").unwrap_or(LogLevel::Info),
+            log: RequestLog::default(),
+        }
+    }
+
+    /// Whether a file with this name may be served. The denylist wins over
+    /// the allowlist, and with an allowlist a file without an extension is
+    /// turned away.
+    pub fn is_extension_allowed(&self, file_name: &str) -> bool {
+        let extension = file_name
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        match extension {
+            Some(extension) if self.deny_extensions.contains(&extension) => false,
+            Some(extension) => self.allow_extensions.is_empty() || self.allow_extensions.contains(&extension),
+            None => self.allow_extensions.is_empty(),
+        }
+    }
+
+    /// The Content-Type `MIME_TYPES` forces for a file, if any. A full file
+    /// name wins over its extension.
+    pub fn mime_override(&self, file_name: &str) -> Option<&str> {
+        let file_name = file_name.to_ascii_lowercase();
+        let by_name = self.mime_overrides.get(file_name.trim_start_matches('.'));
+        let by_extension = || file_name.rsplit_once('.').and_then(|(_, extension)| self.mime_overrides.get(extension));
+        by_name.or_else(by_extension).map(String::as_str)
+    }
+
+    /// The Content-Disposition a file of the given content type gets when the
+    /// request doesn't ask for one, `inline` unless configured otherwise.
+    pub fn disposition_for(&self, content_type: &str) -> &str {
+        self.disposition_by_type
+            .iter()
+            .find(|(prefix, _)| content_type.starts_with(prefix.as_str()))
+            .map_or("inline", |(_, disposition)| disposition.as_str())
+    }
+
+    /// The `max-age` for a served file of the given content type.
+    pub fn max_age_for(&self, content_type: &str) -> u64 {
+        self.cache_max_age_by_type
+            .iter()
+            .find(|(prefix, _)| content_type.starts_with(prefix.as_str()))
+            .map_or(self.cache_max_age, |(_, max_age)| *max_age)
+    }
+}
+
+// Reads a plain-text variable, treating a missing or blank value as unset
+fn var(env: &Env, name: &str) -> Option<String> {
+    env.var(name)
+        .ok()
+        .map(|value| value.to_string().trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn parsed_var<T: std::str::FromStr>(env: &Env, name: &str) -> Option<T> {
+    var(env, name).and_then(|value| value.parse().ok())
+}
+
+// Reads a boolean variable, accepting the usual spellings of true and false
+fn flag_var(env: &Env, name: &str) -> Option<bool> {
+    match var(env, name)?.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+// Reads a JSON object of prefixes to values, ordered so the most specific
+// prefix is tried first. A malformed value is ignored with a warning.
+fn prefix_map_var<T: serde::de::DeserializeOwned>(env: &Env, name: &str) -> Vec<(String, T)> {
+    let Some(value) = var(env, name) else {
+        return Vec::new();
+    };
+    match serde_json::from_str::<HashMap<String, T>>(&value) {
+        Ok(map) => {
+            let mut entries: Vec<(String, T)> = map.into_iter().collect();
+            entries.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+            entries
+        }
+        Err(err) => {
+            console_warn!("Ignoring invalid {}: {}", name, err);
+            Vec::new()
+        }
+    }
+}
+
+// Reads a JSON object of extensions or file names to MIME types. A malformed
+// value is ignored with a warning.
+fn mime_map_var(env: &Env, name: &str) -> HashMap<String, String> {
+    let Some(value) = var(env, name) else {
+        return HashMap::new();
+    };
+    match serde_json::from_str::<HashMap<String, String>>(&value) {
+        Ok(map) => map
+            .into_iter()
+            .map(|(key, mime_type)| (key.trim_start_matches('.').to_ascii_lowercase(), mime_type))
+            .collect(),
+        Err(err) => {
+            console_warn!("Ignoring invalid {}: {}", name, err);
+            HashMap::new()
+        }
+    }
+}
+
+// Reads a JSON object of header names to values. A malformed value is
+// ignored with a warning, as are entries that aren't valid headers.
+fn header_map_var(env: &Env, name: &str) -> Vec<(String, String)> {
+    let Some(value) = var(env, name) else {
+        return Vec::new();
+    };
+    match serde_json::from_str::<HashMap<String, String>>(&value) {
+        Ok(map) => map
+            .into_iter()
+            .filter(|(header, value)| {
+                let valid = http::HeaderName::from_bytes(header.as_bytes()).is_ok() && http::HeaderValue::from_str(value).is_ok();
+                if !valid {
+                    console_warn!("Ignoring invalid {} entry: {}", name, header);
+                }
+                valid
+            })
+            .collect(),
+        Err(err) => {
+            console_warn!("Ignoring invalid {}: {}", name, err);
+            Vec::new()
+        }
+    }
+}
+
+// Reads a list of extensions, normalized to lowercase without a leading dot
+fn extension_list_var(env: &Env, name: &str) -> Vec<String> {
+    list_var(env, name)
+        .into_iter()
+        .map(|extension| extension.trim_start_matches('.').to_ascii_lowercase())
+        .collect()
+}
+
+// Reads a comma-separated list variable, dropping empty entries
+fn list_var(env: &Env, name: &str) -> Vec<String> {
+    var(env, name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,60 @@
+use worker::*;
+
+/// Every method the worker answers, for `Allow` headers.
+pub const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// Response headers a cross-origin script needs to read for ranged media
+// playback and conditional requests
+const EXPOSED_HEADERS: &str = "Content-Length, Content-Range, Accept-Ranges, ETag, Last-Modified";
+
+// How long browsers may reuse a preflight result
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+/// Returns the request's Origin if it's on the configured allowlist.
+/// An allowlist entry of `*` matches every origin.
+pub fn allowed_origin(req: &Request, allowed_origins: &[String]) -> Result<Option<String>> {
+    let origin = match req.headers().get("Origin")? {
+        Some(origin) => origin,
+        None => return Ok(None),
+    };
+    let allowed = allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(&origin));
+    Ok(allowed.then_some(origin))
+}
+
+/// Answers an OPTIONS request, preflight or not, without touching Drive.
+/// `methods` are the ones the requested resource answers to.
+pub fn preflight(req: &Request, allowed_origins: &[String], methods: &str) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Allow", methods)?;
+    if let Some(origin) = allowed_origin(req, allowed_origins)? {
+        set_origin_headers(&headers, &origin)?;
+        headers.set("Access-Control-Allow-Methods", methods)?;
+        if let Some(request_headers) = req.headers().get("Access-Control-Request-Headers")? {
+            headers.set("Access-Control-Allow-Headers", &request_headers)?;
+        }
+        headers.set("Access-Control-Max-Age", PREFLIGHT_MAX_AGE)?;
+    }
+    Ok(Response::empty()?.with_headers(headers).with_status(204))
+}
+
+/// Adds CORS headers to a response for an allowed origin. Headers are copied
+/// onto a fresh set because responses that came from fetch or the cache have
+/// immutable headers.
+pub fn with_cors(response: Response, origin: &str) -> Result<Response> {
+    let headers = Headers::new();
+    for (name, value) in response.headers().entries() {
+        headers.append(&name, &value)?;
+    }
+    set_origin_headers(&headers, origin)?;
+    headers.set("Access-Control-Expose-Headers", EXPOSED_HEADERS)?;
+    Ok(response.with_headers(headers))
+}
+
+fn set_origin_headers(headers: &Headers, origin: &str) -> Result<()> {
+    headers.set("Access-Control-Allow-Origin", origin)?;
+    headers.set("Access-Control-Allow-Methods", ALLOWED_METHODS)?;
+    headers.append("Vary", "Origin")?;
+    Ok(())
+}
@@ -0,0 +1,346 @@
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use worker::*;
+
+use crate::auth::DriveAuth;
+use crate::config::Config;
+
+// Statuses Drive uses for rate limiting and transient backend trouble
+const RETRYABLE_STATUSES: [u16; 5] = [429, 500, 502, 503, 504];
+
+const INITIAL_BACKOFF_MS: u64 = 250;
+const MAX_BACKOFF_MS: u64 = 8000;
+
+/// The error object Drive returns in the body of a failed request.
+#[derive(Deserialize)]
+pub struct DriveError {
+    pub code: u16,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+struct DriveErrorEnvelope {
+    error: DriveError,
+}
+
+/// A Drive API call that didn't produce a usable response.
+pub enum UpstreamError {
+    /// Drive answered with a non-success status
+    Status { status: u16, message: String },
+    /// The request couldn't be made or its response couldn't be read
+    Worker(Error),
+    /// Following shortcuts didn't reach a real file within the depth limit,
+    /// most likely because they form a cycle
+    ShortcutLoop,
+    /// Drive didn't respond within the configured timeout
+    Timeout { timeout_ms: u64 },
+    /// The client went away, so the request to Drive was abandoned
+    Cancelled,
+}
+
+impl From<Error> for UpstreamError {
+    fn from(err: Error) -> Self {
+        UpstreamError::Worker(err)
+    }
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Status { status, message } => write!(f, "Drive returned {}: {}", status, message),
+            UpstreamError::Worker(err) => write!(f, "{}", err),
+            UpstreamError::ShortcutLoop => write!(f, "Shortcut chain is too deep or circular"),
+            UpstreamError::Timeout { timeout_ms } => write!(f, "Drive didn't respond within {}ms", timeout_ms),
+            UpstreamError::Cancelled => write!(f, "Client disconnected before Drive responded"),
+        }
+    }
+}
+
+impl UpstreamError {
+    /// Turns the failure into a client response prefixed with `context`,
+    /// mirroring Drive's status so that e.g. a permission problem stays a 403
+    /// instead of looking like a fault in the worker.
+    pub fn into_response(self, context: &str) -> Result<Response> {
+        let (status, message) = self.client_error()?;
+        Response::error(format!("{}: {}", context, message), status)
+    }
+
+    /// Like `into_response`, but with the JSON error envelope for API clients.
+    pub fn into_json_response(self, config: &Config, context: &str) -> Result<Response> {
+        let (status, message) = self.client_error()?;
+        json_error(config, &format!("{}: {}", context, message), status)
+    }
+
+    /// The status and message to pass on to the client. Worker errors aren't
+    /// Drive's doing, so they stay errors.
+    pub fn client_error(self) -> Result<(u16, String)> {
+        match self {
+            UpstreamError::Status { status, message } => {
+                // Anything outside the error range isn't a status we can pass on
+                let status = if (400..600).contains(&status) { status } else { 502 };
+                Ok((status, message))
+            }
+            UpstreamError::Worker(err) => Err(err),
+            UpstreamError::ShortcutLoop => Ok((508, self.to_string())),
+            UpstreamError::Timeout { .. } => Ok((504, self.to_string())),
+            // Nobody's left to read it, but the log line gets the usual
+            // client-closed-request status
+            UpstreamError::Cancelled => Ok((499, self.to_string())),
+        }
+    }
+}
+
+/// The `{"error": {"code", "message", "request_id"}}` envelope every JSON
+/// error uses.
+pub fn error_envelope(config: &Config, message: &str, status: u16) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "code": status,
+            "message": message,
+            "request_id": config.log.request_id(),
+        }
+    })
+}
+
+/// An error response with the JSON error envelope as its body.
+pub fn json_error(config: &Config, message: &str, status: u16) -> Result<Response> {
+    Ok(Response::from_json(&error_envelope(config, message, status))?.with_status(status))
+}
+
+const FILES_URL: &str = "https://www.googleapis.com/drive/v3/files";
+
+/// The Drive operations the worker is built on, so handlers ask for files
+/// rather than assembling API URLs themselves. `DriveAuth` implements them
+/// against the Drive v3 API. Clients are cloned into work that outlives the
+/// request, like background refreshes and streamed archives.
+pub trait DriveClient: Clone + 'static {
+    /// Runs a files.list call. `params` carries the rest of the query string
+    /// (`q`, `fields`, `orderBy`, `pageToken` and so on).
+    async fn list<T: DeserializeOwned>(&self, config: &Config, params: &str) -> std::result::Result<T, UpstreamError>;
+
+    /// Fetches the given `fields` of a file's metadata.
+    async fn get_metadata<T: DeserializeOwned>(&self, config: &Config, file_id: &str, fields: &str) -> std::result::Result<T, UpstreamError>;
+
+    /// Downloads a file's content, or exports a Google-native file as
+    /// `export_mime_type`. The transfer is abandoned once `signal` fires, as
+    /// it does when the client disconnects.
+    async fn download(&self, config: &Config, file_id: &str, export_mime_type: Option<&str>, headers: Headers, signal: Option<&AbortSignal>) -> std::result::Result<Response, UpstreamError>;
+}
+
+impl DriveClient for DriveAuth {
+    async fn list<T: DeserializeOwned>(&self, config: &Config, params: &str) -> std::result::Result<T, UpstreamError> {
+        let url = format!("{}?{}&supportsAllDrives=true&includeItemsFromAllDrives=true", FILES_URL, params);
+        Ok(get(self, config, &url, Headers::new(), None).await?.json().await?)
+    }
+
+    async fn get_metadata<T: DeserializeOwned>(&self, config: &Config, file_id: &str, fields: &str) -> std::result::Result<T, UpstreamError> {
+        let url = format!(
+            "{}/{}?supportsAllDrives=true&includeItemsFromAllDrives=true&fields={}",
+            FILES_URL, urlencoding::encode(file_id), fields
+        );
+        Ok(get(self, config, &url, Headers::new(), None).await?.json().await?)
+    }
+
+    async fn download(&self, config: &Config, file_id: &str, export_mime_type: Option<&str>, headers: Headers, signal: Option<&AbortSignal>) -> std::result::Result<Response, UpstreamError> {
+        let url = match export_mime_type {
+            Some(export_mime_type) => format!(
+                "{}/{}/export?mimeType={}",
+                FILES_URL, urlencoding::encode(file_id), urlencoding::encode(export_mime_type)
+            ),
+            None => format!(
+                "{}/{}?alt=media&supportsAllDrives=true&includeItemsFromAllDrives=true",
+                FILES_URL, urlencoding::encode(file_id)
+            ),
+        };
+        get(self, config, &url, headers, signal).await
+    }
+}
+
+// Sends a GET to the Drive API, retrying rate-limited and transient failures
+// with exponential backoff, and giving up once `signal` fires. Only
+// successful responses are returned as `Ok`.
+async fn get(auth: &DriveAuth, config: &Config, url: &str, headers: Headers, signal: Option<&AbortSignal>) -> std::result::Result<Response, UpstreamError> {
+    let mut attempt = 1;
+    loop {
+        let request = auth.request(url, headers.clone())?;
+        let mut response = send_with_timeout(request, config.drive_timeout_ms, signal).await?;
+
+        let status = response.status_code();
+        config.log.record_drive_status(status);
+        if (200..300).contains(&status) {
+            return Ok(response);
+        }
+
+        if !RETRYABLE_STATUSES.contains(&status) || attempt >= config.drive_max_attempts {
+            if attempt > 1 {
+                console_warn!("Giving up on Drive request after {} attempts (status {})", attempt, status);
+            }
+            return Err(status_error(&mut response).await);
+        }
+
+        let delay_ms = retry_delay_ms(&response, attempt)?;
+        console_debug!("Drive returned {}, retrying in {}ms (attempt {})", status, delay_ms, attempt);
+        Delay::from(Duration::from_millis(delay_ms)).await;
+        attempt += 1;
+    }
+}
+
+// Races the request against a timer. A timeout isn't retried, since another
+// attempt would most likely run the worker out of time anyway.
+async fn send_with_timeout(request: Request, timeout_ms: u64, signal: Option<&AbortSignal>) -> std::result::Result<Response, UpstreamError> {
+    let fetch = Fetch::Request(request);
+    let send = Box::pin(async {
+        match signal {
+            Some(signal) => fetch.send_with_signal(signal).await,
+            None => fetch.send().await,
+        }
+    });
+    let timer = Delay::from(Duration::from_millis(timeout_ms));
+    let winner = future::select(send, timer).await;
+    match winner {
+        Either::Left((Err(_), _)) if signal.is_some_and(AbortSignal::aborted) => Err(UpstreamError::Cancelled),
+        Either::Left((response, _)) => Ok(response?),
+        Either::Right(_) => Err(UpstreamError::Timeout { timeout_ms }),
+    }
+}
+
+// Honors a Retry-After given in seconds, otherwise doubles the delay on each
+// attempt. Either way the wait is capped so a request can't stall for long.
+fn retry_delay_ms(response: &Response, attempt: u32) -> Result<u64> {
+    let retry_after = response
+        .headers()
+        .get("Retry-After")?
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    let delay_ms = match retry_after {
+        Some(seconds) => seconds.saturating_mul(1000),
+        None => INITIAL_BACKOFF_MS.saturating_mul(1 << (attempt - 1).min(16)),
+    };
+    Ok(delay_ms.min(MAX_BACKOFF_MS))
+}
+
+// Reads Drive's error envelope from a failed response, falling back to a
+// generic message when the body isn't in the documented shape
+async fn status_error(response: &mut Response) -> UpstreamError {
+    let status = response.status_code();
+    let body = response.text().await.unwrap_or_default();
+    match serde_json::from_str::<DriveErrorEnvelope>(&body) {
+        Ok(envelope) => {
+            console_debug!("Drive error {}: {}", envelope.error.code, envelope.error.message);
+            UpstreamError::Status {
+                status,
+                message: envelope.error.message,
+            }
+        }
+        Err(_) => UpstreamError::Status {
+            status,
+            message: format!("Drive returned status {}", status),
+        },
+    }
+}
+
+/// An in-memory Drive for tests, holding files as the JSON Drive would
+/// return for them. It understands the handful of query clauses the worker
+/// builds and pages listings by `pageSize`, so handlers can be run against
+/// it without a network.
+#[cfg(test)]
+pub mod fake {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+    use worker::{AbortSignal, Headers, Response};
+
+    use super::{DriveClient, UpstreamError};
+    use crate::config::Config;
+
+    #[derive(Clone, Default)]
+    pub struct FakeDrive {
+        files: Vec<Value>,
+    }
+
+    impl FakeDrive {
+        /// Adds a file under `parent_id`. `file` needs at least `id`, `name`
+        /// and `mimeType`.
+        pub fn with_file(mut self, parent_id: &str, mut file: Value) -> Self {
+            file["parents"] = json!([parent_id]);
+            self.files.push(file);
+            self
+        }
+
+        fn matches(file: &Value, clause: &str) -> bool {
+            let field = |name: &str| file[name].as_str().unwrap_or_default().to_string();
+            if let Some(parent_id) = clause.strip_suffix(" in parents") {
+                return file["parents"].as_array().is_some_and(|parents| parents.iter().any(|parent| *parent == unquote(parent_id)));
+            }
+            let (field_name, rest) = clause.split_once(' ').expect("clause has a field");
+            let value = field(field_name);
+            match rest.split_once(' ') {
+                Some(("=", literal)) => value == unquote(literal),
+                // Drive's `contains` on names is a case-insensitive prefix match
+                Some(("contains", literal)) if field_name == "name" => value.to_lowercase().starts_with(&unquote(literal).to_lowercase()),
+                Some(("contains", literal)) => value.contains(&unquote(literal)),
+                _ => panic!("FakeDrive doesn't understand the clause {}", clause),
+            }
+        }
+    }
+
+    // Undoes `quote_query_value`
+    fn unquote(literal: &str) -> String {
+        let inner = literal.trim().strip_prefix('\'').and_then(|literal| literal.strip_suffix('\'')).expect("quoted literal");
+        let mut value = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            value.push(if c == '\\' { chars.next().expect("escaped character") } else { c });
+        }
+        value
+    }
+
+    fn param(params: &str, name: &str) -> Option<String> {
+        params
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .map(|value| urlencoding::decode(value).expect("valid encoding").into_owned())
+    }
+
+    impl DriveClient for FakeDrive {
+        async fn list<T: DeserializeOwned>(&self, _config: &Config, params: &str) -> std::result::Result<T, UpstreamError> {
+            let query = param(params, "q").expect("query");
+            let matched: Vec<&Value> = self.files.iter().filter(|file| query.split(" and ").all(|clause| Self::matches(file, clause))).collect();
+            let page_size = param(params, "pageSize").and_then(|size| size.parse().ok()).unwrap_or(matched.len().max(1));
+            let start: usize = param(params, "pageToken").and_then(|token| token.parse().ok()).unwrap_or(0);
+            let end = (start + page_size).min(matched.len());
+            let mut page = json!({ "files": matched[start..end] });
+            if end < matched.len() {
+                page["nextPageToken"] = json!(end.to_string());
+            }
+            Ok(serde_json::from_value(page).expect("listing deserializes"))
+        }
+
+        async fn get_metadata<T: DeserializeOwned>(&self, _config: &Config, file_id: &str, _fields: &str) -> std::result::Result<T, UpstreamError> {
+            match self.files.iter().find(|file| file["id"] == file_id) {
+                Some(file) => Ok(serde_json::from_value(file.clone()).expect("metadata deserializes")),
+                None => Err(UpstreamError::Status { status: 404, message: format!("File not found: {}", file_id) }),
+            }
+        }
+
+        async fn download(&self, _config: &Config, file_id: &str, _export_mime_type: Option<&str>, _headers: Headers, _signal: Option<&AbortSignal>) -> std::result::Result<Response, UpstreamError> {
+            // Responses are backed by JavaScript objects, which don't exist
+            // outside the Workers runtime
+            Err(UpstreamError::Status { status: 501, message: format!("FakeDrive can't download {}", file_id) })
+        }
+    }
+
+    /// Runs a future that never waits on anything outside the fake to
+    /// completion.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future waited on something FakeDrive doesn't provide"),
+        }
+    }
+}
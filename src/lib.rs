@@ -1,5 +1,31 @@
+use std::collections::{HashMap, HashSet};
+
 use worker::*;
+use futures_util::{future, stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+
+mod access;
+mod auth;
+mod coalesce;
+mod compress;
+mod config;
+mod cors;
+mod drive;
+mod log;
+mod manifest;
+mod mime;
+mod ratelimit;
+mod render;
+mod roots;
+mod zip;
+
+use auth::{BasicAuth, DriveAuth};
+use config::Config;
+use drive::{DriveClient, UpstreamError};
+use render::{render_breadcrumbs, render_error_page, render_file_rows, render_grouped_file_rows, render_incomplete_notice, render_page, render_pagination, render_preview, render_summary, render_text_listing, Theme};
+use roots::{Root, Roots};
 
 #[derive(Deserialize, Serialize)]
 struct DriveShorcutDetails {
@@ -19,167 +45,3281 @@ struct DriveFile {
     web_content_link: Option<String>,
     #[serde(rename = "shortcutDetails")]
     shortcut_details: Option<DriveShorcutDetails>,
+    #[serde(rename = "modifiedTime")]
+    modified_time: Option<String>,
+    #[serde(rename = "md5Checksum")]
+    md5_checksum: Option<String>,
+    // Drive reports sizes as int64 strings, and omits them for Google-native files
+    size: Option<String>,
+    #[serde(rename = "thumbnailLink")]
+    thumbnail_link: Option<String>,
+    parents: Option<Vec<String>>,
+    // Only asked for on single-file lookups, and kept out of listings since
+    // it says who a file is shared with
+    #[serde(rename = "permissionIds", skip_serializing)]
+    permission_ids: Option<Vec<String>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct DriveResponse {
     files: Vec<DriveFile>,
+    #[serde(rename = "nextPageToken", skip_serializing_if = "Option::is_none")]
+    next_page_token: Option<String>,
+    // Drive sets this when it couldn't look everywhere, as can happen across
+    // shared drives, so the files may not be all there are
+    #[serde(rename = "incompleteSearch", default, skip_serializing_if = "std::ops::Not::not")]
+    incomplete_search: bool,
+}
+
+// Just the parents of a folder, for walking up the tree
+#[derive(Deserialize)]
+struct DriveParents {
+    parents: Option<Vec<String>>,
 }
 
+// Just the continuation token of a files.list page
+#[derive(Deserialize)]
+struct PageToken {
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+}
+
+// Upper bound on the number of pages fetched for a single listing, so a
+// misconfigured folder can't send us into a runaway request loop
+const MAX_LIST_PAGES: usize = 50;
+
+// Deepest chain of subfolders the router will walk before giving up
+pub(crate) const MAX_FOLDER_DEPTH: usize = 32;
+
+pub(crate) const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+
+// Metadata requested whenever a single file is looked up for serving
+const FILE_FIELDS: &str = "id,name,mimeType,shortcutDetails,modifiedTime,md5Checksum,size,thumbnailLink,webViewLink,webContentLink,parents,permissionIds";
+
+// IDs Drive gives the permissions that open a file to everyone, with or
+// without the link
+const PUBLIC_PERMISSION_IDS: [&str; 2] = ["anyoneWithLink", "anyone"];
+
 #[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> worker::Result<Response> {
-    let url = req.url()?;
-    let path = url.path();
+async fn fetch(req: Request, env: Env, ctx: Context) -> worker::Result<Response> {
+    let started_at = Date::now().as_millis();
+    let config = Config::from_env(&env);
+    if let Some(ray) = req.headers().get("CF-Ray")? {
+        config.log.record_request_id(&ray);
+    }
     
-    // Get API key and folder ID from environment variables
-    let api_key = env.secret("GOOGLE_API_KEY")?.to_string();
-    let folder_id = env.secret("GOOGLE_DRIVE_FOLDER_ID")?.to_string();
+    let result = match handle(&req, &env, &config, &ctx).await {
+        Ok(response) => with_error_page(&config, &req, response).await,
+        Err(err) => {
+            console_error!("Request failed: {}", err);
+            error_page(&config, &req, 500, "Something went wrong while handling this request.", Headers::new())
+        }
+    };
+    let result = result.and_then(|response| with_response_headers(&config, response));
     
-    match path {
-        "/files/" => {
-            // List files in the folder
-            list_files(&api_key, &folder_id).await
+    // Logged here so every request gets exactly one line, whichever branch
+    // answered it
+    let (status, bytes) = match &result {
+        Ok(response) => {
+            let bytes = response.headers().get("Content-Length")?.and_then(|length| length.parse().ok());
+            (response.status_code(), bytes)
         }
-        path if path.starts_with("/files/") => {
-            // Serve a specific file by name
-            let file_name = &path[7..]; // Remove "/files/" prefix
-            let decoded_name = urlencoding::decode(file_name)
-                .map_err(|_| worker::Error::from("Invalid file name encoding"))?
-                .to_string();
-            serve_file_by_name(&api_key, &folder_id, &decoded_name).await
+        Err(_) => (500, None),
+    };
+    config.log.emit(config.log_level, &req, status, bytes, started_at);
+    result
+}
+
+// Adds the RESPONSE_HEADERS set, such as security headers, to a finished
+// response. Headers the response already has are left alone, so nothing
+// configured can clobber a computed Content-Type, Content-Length or the like.
+fn with_response_headers(config: &Config, response: Response) -> worker::Result<Response> {
+    if config.response_headers.is_empty() {
+        return Ok(response);
+    }
+    // Fetched and cached responses have immutable headers, so they're copied
+    let headers = response.headers().clone();
+    for (name, value) in &config.response_headers {
+        if !headers.has(name)? {
+            headers.set(name, value)?;
         }
-        _ => Response::error("Not found", 404)
     }
+    Ok(response.with_headers(headers))
 }
 
-async fn list_files(api_key: &str, folder_id: &str) -> worker::Result<Response> {
-    let url = format!(
-        "https://www.googleapis.com/drive/v3/files?q='{}'+in+parents&supportsAllDrives=true&includeItemsFromAllDrives=true&key={}",
-        folder_id, api_key
-    );
+fn is_text_track_response(response: &Response) -> worker::Result<bool> {
+    let content_type = response.headers().get("Content-Type")?.unwrap_or_default();
+    Ok(mime::is_text_track(content_type.split(';').next().unwrap_or_default().trim()))
+}
+
+// Errors made with `Response::error` are bare text without a Content-Type;
+// they get dressed up here in one place rather than at every call site.
+// Anything else, like a JSON error or a custom 404 page, is left as it is.
+async fn with_error_page(config: &Config, req: &Request, mut response: Response) -> worker::Result<Response> {
+    let status = response.status_code();
+    if status < 400 || response.headers().has("Content-Type")? {
+        return Ok(response);
+    }
+    let message = response.text().await?;
+    // Keep headers like Allow, Retry-After and the CORS set
+    error_page(config, req, status, &message, response.headers().clone())
+}
+
+// An error as a styled page, or as JSON for clients that asked for it
+fn error_page(config: &Config, req: &Request, status: u16, message: &str, headers: Headers) -> worker::Result<Response> {
+    let response = if wants_json(req)? {
+        drive::json_error(config, message, status)?
+    } else {
+        Response::from_html(render_error_page(config, page_theme(req)?, status, message))?.with_status(status)
+    };
+    for (name, value) in response.headers().entries() {
+        headers.set(&name, &value)?;
+    }
+    Ok(response.with_headers(headers))
+}
+
+async fn handle(req: &Request, env: &Env, config: &Config, ctx: &Context) -> worker::Result<Response> {
+    // Preflights and capability probes are answered up front so they never
+    // cost a Drive request
+    if req.method() == Method::Options {
+        let path = req.url()?.path().to_string();
+        let methods = match strip_path_prefix(&path, &config.path_prefix) {
+            Some(PURGE_PATH) => PURGE_METHODS,
+            _ => cors::ALLOWED_METHODS,
+        };
+        return cors::preflight(req, &config.allowed_origins, methods);
+    }
     
-    let request = Request::new(&url, Method::Get)?;
-    let mut response = Fetch::Request(request).send().await?;
-    
-    let status_code = response.status_code();
-    if !(200..300).contains(&status_code) {
-        return Response::error("Failed to fetch files from Google Drive", 500);
-    }
-    
-    let drive_response: DriveResponse = response.json().await?;
-    
-    // Create a simple HTML page listing the files
-    let mut html = String::from(r#"
-<!DOCTYPE html>
-<html>
-<head>
-    <title>Drive Files</title>
-    <style>
-        body { font-family: Arial, sans-serif; margin: 40px; }
-        .file { margin: 10px 0; padding: 10px; border: 1px solid #ddd; border-radius: 5px; }
-        .file-name { font-weight: bold; }
-        .file-type { color: #666; font-size: 0.9em; }
-        a { text-decoration: none; color: #1976d2; }
-        a:hover { text-decoration: underline; }
-    </style>
-</head>
-<body>
-    <h1>Files in Drive Folder</h1>
-"#);
-    
-    for file in drive_response.files {
-        let encoded_name = urlencoding::encode(&file.name);
-        html.push_str(&format!(
-            r#"
-    <div class="file">
-        <div class="file-name">
-            <a href="/files/{}">{}</a>
-        </div>
-        <div class="file-type">{}</div>
-    </div>
-"#,
-            encoded_name, file.name, file.mime_type
-        ));
-    }
-    
-    html.push_str("</body></html>");
-    
-    Response::from_html(html)
-}
-
-async fn serve_file_by_name(api_key: &str, folder_id: &str, file_name: &str) -> worker::Result<Response> {
-    // First, search for the file by name in the specified folder
-    let search_url = format!(
-        "https://www.googleapis.com/drive/v3/files?q=name='{}'+and+'{}'+in+parents&supportsAllDrives=true&includeItemsFromAllDrives=true&fields=files(id,name,mimeType,shortcutDetails)&key={}",
-        file_name.replace("'", "\\'"), folder_id, api_key
-    );
+    if let Some(response) = missing_secrets_response(env, config)? {
+        return Ok(response);
+    }
     
-    let search_request = Request::new(&search_url, Method::Get)?;
-    let mut search_response = Fetch::Request(search_request).send().await?;
+    // Runs ahead of everything that could reach Drive, so a client that's
+    // over its limit can't spend any quota
+    if let Some(response) = ratelimit::check(req, config, ctx).await? {
+        return Ok(response);
+    }
     
-    let search_status = search_response.status_code();
-    if !(200..300).contains(&search_status) {
-        return Response::error("Failed to search for file", 500);
+    if let Some(basic_auth) = BasicAuth::from_env(env) {
+        if !basic_auth.is_authorized(req)? {
+            return BasicAuth::challenge();
+        }
     }
     
-    let search_result: DriveResponse = search_response.json().await?;
+    let mut response = route(req, env, config, ctx).await?;
+    if config.log.is_protected() {
+        response = with_private_caching(response)?;
+    }
     
-    // Check if file was found
-    if search_result.files.is_empty() {
-        return Response::error("File not found", 404);
+    match cors::allowed_origin(req, &config.allowed_origins)? {
+        Some(origin) => cors::with_cors(response, &origin),
+        // Subtitles are useless to a cross-origin player without CORS, and no
+        // more private than any other file here, so every origin gets them
+        None if is_text_track_response(&response)? => cors::with_cors(response, "*"),
+        None => Ok(response),
+    }
+}
+
+// Keeps shared caches from storing what was served out of a folder guarded by
+// an `.access` file, while letting the browser that was let in keep its copy
+fn with_private_caching(response: Response) -> worker::Result<Response> {
+    let headers = Headers::new();
+    for (name, value) in response.headers().entries() {
+        headers.append(&name, &value)?;
+    }
+    let cache_control = headers.get("Cache-Control")?.unwrap_or_default();
+    match cache_control.strip_prefix("public") {
+        Some(rest) => headers.set("Cache-Control", &format!("private{}", rest))?,
+        None if cache_control.is_empty() => headers.set("Cache-Control", "private")?,
+        None => {}
+    }
+    Ok(response.with_headers(headers))
+}
+
+thread_local! {
+    // Isolates are reused across requests, so this keeps a misconfigured
+    // deployment from repeating the same warning on every one of them
+    static REPORTED_MISSING_SECRETS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+fn has_secret(env: &Env, name: &str) -> bool {
+    env.secret(name).is_ok_and(|secret| !secret.to_string().trim().is_empty())
+}
+
+// Catches a fresh deployment that's missing its secrets before anything
+// tries to use them, naming what's missing instead of failing with a bare
+// 500. With PRODUCTION set the names only go to the log.
+fn missing_secrets_response(env: &Env, config: &Config) -> worker::Result<Option<Response>> {
+    let mut missing = Vec::new();
+    if !has_secret(env, "GOOGLE_DRIVE_FOLDER_ID") {
+        missing.push("GOOGLE_DRIVE_FOLDER_ID");
+    }
+    if !has_secret(env, "GOOGLE_SERVICE_ACCOUNT_KEY") && !has_secret(env, "GOOGLE_API_KEY") {
+        missing.push("GOOGLE_API_KEY (or GOOGLE_SERVICE_ACCOUNT_KEY)");
+    }
+    if missing.is_empty() {
+        return Ok(None);
     }
     
-    // Use the first matching file (in case of duplicates)
-    let file_info = &search_result.files[0];
+    let message = format!("The worker is missing required configuration: {}", missing.join(", "));
+    if !REPORTED_MISSING_SECRETS.with(|reported| reported.replace(true)) {
+        console_error!("{}", message);
+    }
+    let message = if config.production { "The worker is not configured correctly." } else { &message };
+    Ok(Some(Response::error(message, 503)?))
+}
 
-    if let Some(shortcut_details) = &file_info.shortcut_details {
-        console_debug!("File is a shortcut, resolving target ID: {}", shortcut_details.target_id);
-        // If it's a shortcut, we need to get the target file info
-        let target_file_id = &shortcut_details.target_id;
-        let target_url = format!(
-            "https://www.googleapis.com/drive/v3/files/{}?supportsAllDrives=true&includeItemsFromAllDrives=true&key={}",
-            target_file_id, api_key
-        );
-        
-        let target_request = Request::new(&target_url, Method::Get)?;
-        let mut target_response = Fetch::Request(target_request).send().await?;
-        
-        let target_status = target_response.status_code();
-        if !(200..300).contains(&target_status) {
-            return Response::error("Failed to fetch target file of shortcut", 500);
+// The endpoints the router knows about, along with the root folder each one
+// resolved to
+enum Route<'a> {
+    Thumbnail(Root<'a>),
+    Preview(Root<'a>),
+    Meta(Root<'a>),
+    Zip(Root<'a>),
+    Raw(Root<'a>),
+    Favicon,
+    Robots,
+    ApiList(Root<'a>),
+    Folders(Root<'a>),
+    ById(&'a str),
+    Slug(&'a str),
+    Sitemap,
+    Manifest,
+    Search(Root<'a>),
+    Home(Root<'a>),
+    Files(Root<'a>),
+}
+
+async fn route(req: &Request, env: &Env, config: &Config, ctx: &Context) -> worker::Result<Response> {
+    let url = req.url()?;
+    // Everything below works on the path as if the worker were mounted at
+    // the root; links it generates add the prefix back
+    let path = match strip_path_prefix(url.path(), &config.path_prefix) {
+        Some(path) => path,
+        None => return Response::error("Not found", 404),
+    };
+    
+    // Checked before credentials are loaded so that a broken key is reported
+    // as unhealthy rather than failing the request
+    if path == "/health" {
+        if !is_supported_method(req) {
+            return method_not_allowed();
         }
-        
-        let target_file_info: DriveFile = target_response.json().await?;
+        return health_check(env, config).await;
+    }
+    
+    // Only answers to the shared secret, so it's kept apart from the public
+    // routes and their method rules
+    if path == PURGE_PATH {
+        if req.method() != Method::Post {
+            let headers = Headers::new();
+            headers.set("Allow", PURGE_METHODS)?;
+            return Ok(Response::error("Method not allowed", 405)?.with_headers(headers));
+        }
+        if !auth::is_purge_authorized(env, req)? {
+            return Response::error("Forbidden", 403);
+        }
+        return purge(env, config, req).await;
+    }
+    
+    let roots = Roots::parse(&env.secret("GOOGLE_DRIVE_FOLDER_ID")?.to_string())?;
+    let route = match path {
+        path if path.starts_with("/thumb/") => roots.for_nested_path(&path[7..]).map(Route::Thumbnail), // Remove "/thumb/" prefix
+        path if path.starts_with("/preview/") => roots.for_nested_path(&path[9..]).map(Route::Preview), // Remove "/preview/" prefix
+        path if path.starts_with("/meta/") => roots.for_nested_path(&path[6..]).map(Route::Meta), // Remove "/meta/" prefix
+        path if path.starts_with("/zip/") => roots.for_nested_path(&path[5..]).map(Route::Zip), // Remove "/zip/" prefix
+        path if path.starts_with("/raw/") => roots.for_nested_path(&path[5..]).map(Route::Raw), // Remove "/raw/" prefix
+        "/api/list" => roots.for_nested_path("").map(Route::ApiList),
+        path if path.starts_with("/api/list/") => roots.for_nested_path(&path[10..]).map(Route::ApiList), // Remove "/api/list/" prefix
+        "/folders" => roots.for_nested_path("").map(Route::Folders),
+        path if path.starts_with("/folders/") => roots.for_nested_path(&path[9..]).map(Route::Folders), // Remove "/folders/" prefix
+        path if path.starts_with("/id/") => Some(Route::ById(&path[4..])), // Remove "/id/" prefix
+        path if path.starts_with("/s/") => Some(Route::Slug(&path[3..])), // Remove "/s/" prefix
+        "/favicon.ico" => Some(Route::Favicon),
+        "/robots.txt" => Some(Route::Robots),
+        "/sitemap.xml" => Some(Route::Sitemap),
+        "/manifest.json" => Some(Route::Manifest),
+        "/search" => roots.by_name(query_param(&url, "root").as_deref()).map(Route::Search),
+        // A bare domain leads to the listing; with several roots there's no
+        // one listing to lead to
+        "/" if config.root_listing => roots.by_name(None).map(Route::Files),
+        "/" => roots.by_name(None).map(Route::Home),
+        path => roots.for_files_path(path).map(Route::Files),
+    };
+    let route = match route {
+        Some(route) => route,
+        None => {
+            // Misses are only dressed up when it's clear which root they
+            // belong to, and a credentials problem shouldn't turn one into a 500
+            let fallback = Response::error("Not found", 404)?;
+            return match (roots.by_name(None), DriveAuth::from_env(env, config).await) {
+                (Some(root), Ok(auth)) => not_found_page(&auth, config, root.folder_id, req, fallback).await,
+                _ => Ok(fallback),
+            };
+        }
+    };
+    
+    // Only checked once the path is known to be valid, so a wrong method and
+    // a wrong path stay distinguishable
+    if !is_supported_method(req) {
+        return method_not_allowed();
+    }
+    
+    // Get Drive credentials from environment variables
+    let auth = DriveAuth::from_env(env, config).await?;
+    
+    match route {
+        Route::Thumbnail(root) => {
+            let location = match resolve_permitted_location(&auth, config, root.folder_id, root.rest, req).await? {
+                Ok(location) => location,
+                Err(response) => return Ok(response),
+            };
+            serve_thumbnail(&auth, config, &location.folder_id, &location.file_name, req).await
+        }
+        Route::Preview(root) => {
+            let location = match resolve_permitted_location(&auth, config, root.folder_id, root.rest, req).await? {
+                Ok(location) => location,
+                Err(response) => return Ok(response),
+            };
+            serve_preview(&auth, config, &location.folder_id, &location.file_name, req).await
+        }
+        Route::Zip(root) => match resolve_folder(&auth, config, &root, req).await? {
+            Ok((folder_id, folder_name)) => serve_zip(&auth, config, env, &folder_id, &folder_name, req).await,
+            Err(response) => Ok(response),
+        },
+        Route::ApiList(root) => match resolve_folder(&auth, config, &root, req).await? {
+            Ok((folder_id, _)) => api_list(&auth, config, &folder_id, req).await,
+            Err(mut response) => drive::json_error(config, &response.text().await?, response.status_code()),
+        },
+        Route::Folders(root) => match resolve_folder(&auth, config, &root, req).await? {
+            Ok((folder_id, _)) => list_folders(&auth, config, &folder_id, req).await,
+            Err(mut response) => drive::json_error(config, &response.text().await?, response.status_code()),
+        },
+        Route::Raw(root) => {
+            let location = match resolve_permitted_location(&auth, config, root.folder_id, root.rest, req).await? {
+                Ok(location) => location,
+                Err(response) => return Ok(response),
+            };
+            let file_id = query_param(&url, "id");
+            match resolve_file_by_name(&auth, config, &location.folder_id, &location.file_name, file_id.as_deref()).await {
+                Ok(NameLookup::Found(file_info)) if file_info.mime_type != FOLDER_MIME_TYPE => {
+                    // No Content-Disposition at all, so embedding pages and
+                    // fetch() go by the content type alone
+                    serve_file_by_id(&auth, config, &file_info, Disposition::Omitted, req, ctx).await
+                }
+                Ok(NameLookup::Found(_)) | Ok(NameLookup::NotFound) => Response::error("File not found", 404),
+                Ok(NameLookup::Ambiguous(files)) => disambiguation_response(config, &location.file_name, &files, req),
+                Err(err) => err.into_response("Failed to look up file"),
+            }
+        }
+        Route::Favicon => serve_favicon(&auth, config, &roots, req, ctx).await,
+        Route::Robots => serve_robots(&auth, config, &roots, req, ctx).await,
+        Route::Meta(root) => {
+            let location = match resolve_permitted_location(&auth, config, root.folder_id, root.rest, req).await? {
+                Ok(location) => location,
+                Err(mut response) => return drive::json_error(config, &response.text().await?, response.status_code()),
+            };
+            serve_metadata(&auth, config, &location.folder_id, &location.file_name, req).await
+        }
+        Route::ById(file_id) => serve_file_by_drive_id(&auth, config, &roots, file_id, req, ctx).await,
+        Route::Slug(slug) => serve_file_by_slug(&auth, config, &roots, slug, req, ctx).await,
+        Route::Sitemap | Route::Manifest => {
+            let entries = match manifest::entries(&auth, config, &roots, ctx, is_truthy_param(&url, "refresh")).await {
+                Ok(entries) => entries,
+                Err(err) => {
+                    console_error!("Failed to build manifest: {}", err);
+                    return err.into_response("Failed to list files");
+                }
+            };
+            match route {
+                Route::Sitemap => {
+                    let xml = manifest::sitemap(&external_url(config, req)?.origin().ascii_serialization(), &entries);
+                    listing_response(config, req, xml.into_bytes(), "application/xml")
+                }
+                _ => listing_response(config, req, serde_json::to_vec(&entries)?, "application/json"),
+            }
+        }
+        Route::Search(root) => search_files(&auth, config, root.folder_id, &root.base_path(&config.path_prefix), req).await,
+        // `/files/` stays the canonical address of the listing
+        Route::Home(root) => {
+            let mut url = external_url(config, req)?;
+            url.set_path(&root.base_path(&config.path_prefix));
+            Response::redirect_with_status(url, 301)
+        }
+        Route::Files(root) => {
+            let response = serve_files_path(&auth, config, &root, req, ctx).await?;
+            if response.status_code() == 404 {
+                return not_found_page(&auth, config, root.folder_id, req, response).await;
+            }
+            Ok(response)
+        }
+    }
+}
+
+// Resolves a route's path to a folder, whether or not it ends in a slash,
+// along with the folder's name (the root's prefix for the root itself)
+async fn resolve_folder(auth: &impl DriveClient, config: &Config, root: &Root<'_>, req: &Request) -> worker::Result<std::result::Result<(String, String), Response>> {
+    let location = match resolve_permitted_location(auth, config, root.folder_id, root.rest, req).await? {
+        Ok(location) => location,
+        Err(response) => return Ok(Err(response)),
+    };
+    if location.file_name.is_empty() {
+        let name = root.rest.trim_end_matches('/').rsplit('/').next().filter(|name| !name.is_empty());
+        let name = name.and_then(decode_path_segment).unwrap_or_else(|| root.prefix.to_string());
+        return Ok(Ok((location.folder_id, name)));
+    }
+    match find_child_folder(auth, config, &location.folder_id, &location.file_name).await {
+        Ok(Some(folder_id)) => match access::check(auth, config, std::slice::from_ref(&folder_id), req).await? {
+            Some(response) => Ok(Err(response)),
+            None => Ok(Ok((folder_id, location.file_name))),
+        },
+        Ok(None) => Ok(Err(Response::error("Folder not found", 404)?)),
+        Err(err) => Ok(Err(err.into_response("Failed to look up folder")?)),
+    }
+}
 
-        return serve_file_by_id(api_key, &target_file_info).await;
+async fn serve_files_path(auth: &impl DriveClient, config: &Config, root: &Root<'_>, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    let location = match resolve_permitted_location(auth, config, root.folder_id, root.rest, req).await? {
+        Ok(location) => location,
+        Err(response) => return Ok(response),
+    };
+    
+    if location.file_name.is_empty() {
+        // List files in the folder
+        list_files(auth, config, &location.folder_id, &root.base_path(&config.path_prefix), req, ctx).await
     } else {
-        return serve_file_by_id(api_key, file_info).await;
+        // Serve a specific file by name
+        let file_id = query_param(&req.url()?, "id");
+        serve_file_by_name(auth, config, &location.folder_id, &location.file_name, file_id.as_deref(), req, ctx).await
     }
 }
 
-async fn serve_file_by_id(api_key: &str, file_info: &DriveFile) -> worker::Result<Response> {
-    let file_id = &file_info.id;
+// Looks up a file the worker serves from the root folder in its own right,
+// like favicon.ico. A root the request isn't let into has none, so the
+// built-in answer goes out rather than a private folder's file.
+async fn root_file(auth: &impl DriveClient, config: &Config, root_folder_id: &str, name: &str, req: &Request) -> std::result::Result<Option<DriveFile>, UpstreamError> {
+    if !access::is_allowed(auth, config, root_folder_id, req).await? {
+        return Ok(None);
+    }
+    match resolve_file_by_name(auth, config, root_folder_id, name, None).await? {
+        NameLookup::Found(file_info) if file_info.mime_type != FOLDER_MIME_TYPE => Ok(Some(*file_info)),
+        _ => Ok(None),
+    }
+}
+
+// Served at /favicon.ico when the root folder doesn't have its own
+const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+// The built-in icon never changes, so it can be cached for a long time
+const DEFAULT_FAVICON_MAX_AGE: u64 = 7 * 24 * 3600;
+
+// Browsers ask for /favicon.ico on every visit, so it's answered with the
+// root folder's `favicon.ico` when there is one, and a built-in icon rather
+// than a 404 otherwise. With several roots there's no one folder to look in.
+async fn serve_favicon(auth: &impl DriveClient, config: &Config, roots: &Roots, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    if let Some(root) = roots.by_name(None) {
+        match root_file(auth, config, root.folder_id, "favicon.ico", req).await {
+            Ok(Some(file_info)) => return serve_file_by_id(auth, config, &file_info, Disposition::Omitted, req, ctx).await,
+            Ok(None) => {}
+            Err(err) => console_warn!("Failed to look up favicon.ico: {}", err),
+        }
+    }
     
-    // Download the file content
-    let download_url = format!(
-        "https://www.googleapis.com/drive/v3/files/{}?alt=media&supportsAllDrives=true&includeItemsFromAllDrives=true&key={}",
-        file_id, api_key
-    );
+    let headers = Headers::new();
+    headers.set("Content-Type", "image/x-icon")?;
+    headers.set("Content-Length", &DEFAULT_FAVICON.len().to_string())?;
+    headers.set("Cache-Control", &format!("public, max-age={}", DEFAULT_FAVICON_MAX_AGE))?;
+    let response = match req.method() {
+        Method::Head => Response::empty()?,
+        _ => Response::from_bytes(DEFAULT_FAVICON.to_vec())?,
+    };
+    Ok(response.with_headers(headers))
+}
+
+// Served at /robots.txt when the root folder doesn't have its own, with
+// ROBOTS=disallow or without it
+const ROBOTS_DISALLOW: &str = "User-agent: *\nDisallow: /\n";
+const ROBOTS_ALLOW: &str = "User-agent: *\nDisallow:\n";
+
+// Crawlers fetch robots.txt often and it rarely changes
+const ROBOTS_MAX_AGE: u64 = 24 * 3600;
+
+// Answers /robots.txt with the root folder's `robots.txt` when there is one,
+// and otherwise with rules that either keep crawlers out or let them in,
+// depending on ROBOTS. With several roots there's no one folder to look in.
+async fn serve_robots(auth: &impl DriveClient, config: &Config, roots: &Roots, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    if let Some(root) = roots.by_name(None) {
+        match root_file(auth, config, root.folder_id, "robots.txt", req).await {
+            Ok(Some(file_info)) => return serve_file_by_id(auth, config, &file_info, Disposition::Omitted, req, ctx).await,
+            Ok(None) => {}
+            Err(err) => console_warn!("Failed to look up robots.txt: {}", err),
+        }
+    }
     
-    let download_request = Request::new(&download_url, Method::Get)?;
-    let mut download_response = Fetch::Request(download_request).send().await?;
+    let rules = if config.robots_disallow { ROBOTS_DISALLOW } else { ROBOTS_ALLOW };
+    let headers = Headers::new();
+    headers.set("Content-Type", "text/plain; charset=utf-8")?;
+    headers.set("Content-Length", &rules.len().to_string())?;
+    headers.set("Cache-Control", &format!("public, max-age={}", ROBOTS_MAX_AGE))?;
+    let response = match req.method() {
+        Method::Head => Response::empty()?,
+        _ => Response::ok(rules)?,
+    };
+    Ok(response.with_headers(headers))
+}
+
+// Name of the page served in place of a bare 404, looked up in the root folder
+const NOT_FOUND_FILE_NAME: &str = "404.html";
+
+// Swaps a 404 for the root folder's `404.html` when there is one. Anything
+// going wrong along the way leaves the original response in place, since a
+// flaky custom page shouldn't hide the fact that the file is missing.
+async fn not_found_page(auth: &impl DriveClient, config: &Config, root_folder_id: &str, req: &Request, fallback: Response) -> worker::Result<Response> {
+    let page = async {
+        // A private root's page is only for those it lets in
+        if !access::is_allowed(auth, config, root_folder_id, req).await? {
+            return Ok(None);
+        }
+        let file_info = match resolve_file_by_name(auth, config, root_folder_id, NOT_FOUND_FILE_NAME, None).await? {
+            NameLookup::Found(file_info) if !file_info.mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX) => file_info,
+            _ => return Ok(None),
+        };
+        let mut response = auth.download(config, &file_info.id, None, Headers::new(), None).await?;
+        Ok::<Option<Vec<u8>>, UpstreamError>(Some(response.bytes().await?))
+    }.await;
     
-    let download_status = download_response.status_code();
-    if !(200..300).contains(&download_status) {
-        return Response::error("Failed to download file", 500);
+    match page {
+        Ok(Some(body)) => Ok(generated_response(config, req, body, "text/html; charset=utf-8")?.with_status(404)),
+        Ok(None) => Ok(fallback),
+        Err(err) => {
+            console_warn!("Failed to load {}: {}", NOT_FOUND_FILE_NAME, err);
+            Ok(fallback)
+        }
+    }
+}
+
+// Drops cached copies so changes in Drive show up straight away. `?path=`
+// names a folder or file the way it's requested (`/files/docs/` or
+// `/files/docs/a.pdf`): a folder's listings are dropped, and for a file its
+// folder's listings along with the file's own edge cache entries. Without a
+// path every listing goes. The manifest is dropped either way.
+async fn purge(env: &Env, config: &Config, req: &Request) -> worker::Result<Response> {
+    let url = req.url()?;
+    let mut listing_prefix = String::from("listing:");
+    let mut cached_file = None;
+    if let Some(path) = query_param(&url, "path") {
+        let roots = Roots::parse(&env.secret("GOOGLE_DRIVE_FOLDER_ID")?.to_string())?;
+        let Some(root) = roots.for_files_path(&path) else {
+            return Response::error("Path is outside of the served folders", 400);
+        };
+        let auth = DriveAuth::from_env(env, config).await?;
+        let location = match resolve_location(&auth, config, root.folder_id, root.rest).await? {
+            Ok(location) => location,
+            Err(response) => return Ok(response),
+        };
+        if !location.file_name.is_empty() {
+            match resolve_file_by_name(&auth, config, &location.folder_id, &location.file_name, None).await {
+                Ok(NameLookup::Found(file_info)) if file_info.mime_type == FOLDER_MIME_TYPE => {
+                    listing_prefix.push_str(&format!("{}:", file_info.id));
+                }
+                Ok(NameLookup::Found(file_info)) => {
+                    listing_prefix.push_str(&format!("{}:", location.folder_id));
+                    let formats = export_formats(&file_info.mime_type);
+                    cached_file = Some((file_info, formats));
+                }
+                // Gone from Drive, so at most its folder's listings are stale
+                Ok(NameLookup::NotFound) | Ok(NameLookup::Ambiguous(_)) => {
+                    listing_prefix.push_str(&format!("{}:", location.folder_id));
+                }
+                Err(err) => return err.into_response("Failed to look up file"),
+            }
+        } else {
+            listing_prefix.push_str(&format!("{}:", location.folder_id));
+        }
     }
     
-    // Create response with appropriate headers
+    if let Some(kv) = &config.kv {
+        let mut cursor = None;
+        loop {
+            let mut list = kv.list().prefix(listing_prefix.clone());
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+            let listed = list.execute().await.map_err(|err| Error::from(format!("Failed to list cached listings: {:?}", err)))?;
+            for key in listed.keys {
+                kv.delete(&key.name).await.map_err(|err| Error::from(format!("Failed to delete {}: {:?}", key.name, err)))?;
+            }
+            match listed.cursor {
+                Some(next) if !listed.list_complete => cursor = Some(next),
+                _ => break,
+            }
+        }
+        kv.delete(manifest::CACHE_KEY).await.map_err(|err| Error::from(format!("Failed to delete manifest: {:?}", err)))?;
+    }
+    
+    if let Some((file_info, formats)) = cached_file {
+        let cache = Cache::default();
+        let format_names = std::iter::once(None).chain(formats.iter().map(|(format_name, _)| Some(*format_name)));
+        for format_name in format_names {
+            for disposition in CACHED_DISPOSITIONS {
+                cache.delete(file_cache_key(&url, &file_info, format_name, disposition), false).await?;
+            }
+        }
+    }
+    
+    console_log!("Purged caches for {}", query_param(&url, "path").as_deref().unwrap_or("everything"));
+    Ok(Response::empty()?.with_status(204))
+}
+
+// The purge endpoint, which unlike everything else only takes POST
+const PURGE_PATH: &str = "/admin/purge";
+const PURGE_METHODS: &str = "POST, OPTIONS";
+
+// The part of a request path below PATH_PREFIX, or None when the request
+// falls outside of it
+fn strip_path_prefix<'a>(path: &'a str, path_prefix: &str) -> Option<&'a str> {
+    match path.strip_prefix(path_prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+// What the public routes answer to. OPTIONS never reaches the router, and
+// the purge endpoint takes POST but checks for it on its own, so GET and HEAD
+// are all that's left.
+fn is_supported_method(req: &Request) -> bool {
+    matches!(req.method(), Method::Get | Method::Head)
+}
+
+fn method_not_allowed() -> worker::Result<Response> {
+    let headers = Headers::new();
+    headers.set("Allow", cors::ALLOWED_METHODS)?;
+    Ok(Response::error("Method not allowed", 405)?.with_headers(headers))
+}
+
+// Confirms each root folder can be fetched with the configured credentials,
+// asking only for its ID to keep the check cheap enough to poll
+async fn health_check(env: &Env, config: &Config) -> worker::Result<Response> {
+    let result = async {
+        let auth = DriveAuth::from_env(env, config).await?;
+        let roots = Roots::parse(&env.secret("GOOGLE_DRIVE_FOLDER_ID")?.to_string())?;
+        for folder_id in roots.folder_ids() {
+            auth.get_metadata::<serde::de::IgnoredAny>(config, folder_id, "id").await?;
+        }
+        Ok::<(), UpstreamError>(())
+    }.await;
+    
+    let (body, status) = match result {
+        Ok(()) => (serde_json::json!({ "status": "ok" }), 200),
+        Err(err) => {
+            console_error!("Health check failed: {}", err);
+            (serde_json::json!({ "status": "error", "error": err.to_string() }), 503)
+        }
+    };
     let headers = Headers::new();
-    headers.set("Content-Type", &file_info.mime_type)?;
-    headers.set("Content-Disposition", &format!("inline; filename=\"{}\"", file_info.name))?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::from_json(&body)?.with_status(status).with_headers(headers))
+}
+
+// Where a request path points: the folder it resolves to, plus the final
+// segment, which is empty when the path names the folder itself
+struct Location {
+    folder_id: String,
+    file_name: String,
+    // Every folder walked through on the way, starting with the root
+    folder_ids: Vec<String>,
+}
+
+// Decodes one segment of a URL path. Unlike a query string, a path has no
+// special meaning for `+`, so it stays a plus sign; only `%XX` escapes are
+// decoded, and a `%` that doesn't start one is kept as is, the way browsers
+// treat it. None means the escapes don't decode to UTF-8.
+pub(crate) fn decode_path_segment(segment: &str) -> Option<String> {
+    String::from_utf8(percent_decode(segment).into_iter().map(|(byte, _)| byte).collect()).ok()
+}
+
+// The bytes a path segment decodes to, each with the offset in the segment
+// it came from
+fn percent_decode(segment: &str) -> Vec<(u8, usize)> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes.get(i + 1..i + 3) {
+            Some(&[high, low]) if bytes[i] == b'%' && high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                let hex_value = |digit: u8| (digit as char).to_digit(16).unwrap_or(0) as u8;
+                decoded.push((hex_value(high) << 4 | hex_value(low), i));
+                i += 3;
+            }
+            _ => {
+                decoded.push((bytes[i], i));
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+// Says where a segment that `decode_path_segment` rejected goes wrong, for
+// debugging whatever encoded the link. The bytes are only ever given as
+// `%XX` escapes, so nothing the client sent is reflected as is.
+fn describe_bad_encoding(segment: &str, position: usize) -> String {
+    let decoded = percent_decode(segment);
+    let bytes: Vec<u8> = decoded.iter().map(|(byte, _)| *byte).collect();
+    let Err(err) = std::str::from_utf8(&bytes) else {
+        return String::from("Invalid path: bad percent-encoding");
+    };
+    let start = err.valid_up_to();
+    let end = err.error_len().map_or(bytes.len(), |len| start + len);
+    let sequence: String = bytes[start..end].iter().map(|byte| format!("%{:02X}", byte)).collect();
+    format!(
+        "Invalid path: bad percent-encoding in segment {} at offset {}: {} isn't valid UTF-8",
+        position, decoded[start].1, sequence
+    )
+}
+
+// Resolves a slash-separated path relative to `root_folder_id` by walking down
+// one subfolder per segment. Paths that can't be resolved produce the
+// response to send back instead.
+async fn resolve_location(auth: &impl DriveClient, config: &Config, root_folder_id: &str, path: &str) -> worker::Result<std::result::Result<Location, Response>> {
+    // Every segment but the last names a subfolder; the last one is either a
+    // file name or empty for a folder listing
+    let mut segments = Vec::new();
+    for (index, segment) in path.split('/').enumerate() {
+        // Errors go through `with_error_page`, so clients asking for JSON
+        // get the message in the usual envelope
+        let Some(decoded) = decode_path_segment(segment) else {
+            return Ok(Err(Response::error(describe_bad_encoding(segment, index + 1), 400)?));
+        };
+        match validate_segment(&decoded) {
+            Ok(()) => segments.push(decoded),
+            Err(reason) => return Ok(Err(Response::error(format!("Invalid path: {}", reason), 400)?)),
+        }
+    }
+    let file_name = segments.pop().unwrap_or_default();
+    
+    if segments.len() > MAX_FOLDER_DEPTH {
+        return Ok(Err(Response::error("Not found", 404)?));
+    }
+    
+    let mut folder_id = root_folder_id.to_string();
+    let mut folder_ids = vec![folder_id.clone()];
+    for folder_name in &segments {
+        match find_child_folder(auth, config, &folder_id, folder_name).await {
+            Ok(Some(child_folder_id)) => folder_id = child_folder_id,
+            Ok(None) => return Ok(Err(Response::error("Not found", 404)?)),
+            Err(err) => return Ok(Err(err.into_response("Failed to search for folder")?)),
+        }
+        folder_ids.push(folder_id.clone());
+    }
+    
+    Ok(Ok(Location { folder_id, file_name, folder_ids }))
+}
+
+// Like `resolve_location`, but also holds the request to the `.access` file
+// of every folder along the way, and never lets the files themselves out
+async fn resolve_permitted_location(auth: &impl DriveClient, config: &Config, root_folder_id: &str, path: &str, req: &Request) -> worker::Result<std::result::Result<Location, Response>> {
+    let location = match resolve_location(auth, config, root_folder_id, path).await? {
+        Ok(location) => location,
+        Err(response) => return Ok(Err(response)),
+    };
+    if location.file_name == access::ACCESS_FILE_NAME {
+        return Ok(Err(Response::error("Not found", 404)?));
+    }
+    match access::check(auth, config, &location.folder_ids, req).await? {
+        Some(response) => Ok(Err(response)),
+        None => Ok(Ok(location)),
+    }
+}
+
+// Checks a decoded path segment before it can reach a Drive query. Encoded
+// separators, dot segments and control characters (including NUL and
+// newlines) are never part of a legitimate link generated by the listing.
+fn validate_segment(segment: &str) -> std::result::Result<(), &'static str> {
+    if segment.contains('/') {
+        return Err("names can't contain path separators");
+    }
+    if segment == "." || segment == ".." {
+        return Err("relative path segments aren't allowed");
+    }
+    if segment.chars().any(char::is_control) {
+        return Err("names can't contain control characters");
+    }
+    Ok(())
+}
+
+// Quotes a value as a string literal for the Drive query language, which
+// escapes single quotes and backslashes with a backslash
+fn quote_query_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+// Builds the query string selecting the children of `folder_id` for a
+// files.list call, starting with the percent-encoded `q` parameter. Each
+// predicate is a field and operator paired with the value to compare against,
+// which gets quoted here, so no caller has to build query syntax by hand and
+// characters like `+`, `&` and `#` in names can't leak out of the parameter.
+//
+// With SHARED_DRIVE_ID set, the search is also limited to that shared drive
+// (`corpora=drive`) instead of everything the credentials can see. Drive
+// still needs supportsAllDrives and includeItemsFromAllDrives alongside it,
+// which every files.list URL already sends.
+fn build_query(config: &Config, folder_id: &str, predicates: &[(&str, &str)]) -> String {
+    let clauses = predicates.iter().map(|(field, value)| predicate_clause(field, value)).collect();
+    build_query_from_clauses(config, folder_id, clauses)
+}
+
+fn predicate_clause(field: &str, value: &str) -> String {
+    format!("{} {}", field, quote_query_value(value))
+}
+
+// Like `build_query`, for callers that need clauses other than a plain
+// conjunction of predicates. Each clause has to be built with
+// `predicate_clause` so its values stay quoted.
+fn build_query_from_clauses(config: &Config, folder_id: &str, mut clauses: Vec<String>) -> String {
+    clauses.push(format!("{} in parents", quote_query_value(folder_id)));
+    let mut query = format!("q={}", urlencoding::encode(&clauses.join(" and ")));
+    if let Some(drive_id) = &config.shared_drive_id {
+        query.push_str(&format!("&driveId={}&corpora=drive", urlencoding::encode(drive_id)));
+    }
+    query
+}
+
+// Looks up a direct subfolder of `parent_id` by name, returning its ID if it exists
+async fn find_child_folder(auth: &impl DriveClient, config: &Config, parent_id: &str, folder_name: &str) -> std::result::Result<Option<String>, UpstreamError> {
+    if folder_name.is_empty() {
+        return Ok(None);
+    }
+    
+    let query = build_query(config, parent_id, &[("name =", folder_name), ("mimeType =", FOLDER_MIME_TYPE)]);
+    let search_result: DriveResponse = auth.list(config, &format!("{}&fields=files(id,name,mimeType)", query)).await?;
+    Ok(search_result.files.into_iter().next().map(|folder| folder.id))
+}
+
+// Whether the client asked for a machine-readable listing, either explicitly
+// via `?format=json` or through its Accept header
+fn wants_json(req: &Request) -> worker::Result<bool> {
+    if let Some(format) = query_param(&req.url()?, "format") {
+        return Ok(format.eq_ignore_ascii_case("json"));
+    }
+    let accept = req.headers().get("Accept")?.unwrap_or_default();
+    Ok(accept.contains("application/json"))
+}
+
+// The forms a folder listing can be rendered in
+#[derive(Clone, Copy, PartialEq)]
+enum ListingFormat {
+    Html,
+    Json,
+    // One name per line, for terminals
+    Text,
+}
+
+// Picks a listing's format from `?format=` or, failing that, the Accept
+// header. Plain text has to be asked for without HTML alongside it, so
+// browsers keep getting HTML.
+fn listing_format(req: &Request) -> worker::Result<ListingFormat> {
+    if let Some(format) = query_param(&req.url()?, "format") {
+        return Ok(match format.to_ascii_lowercase().as_str() {
+            "json" => ListingFormat::Json,
+            "text" => ListingFormat::Text,
+            _ => ListingFormat::Html,
+        });
+    }
+    let accept = req.headers().get("Accept")?.unwrap_or_default();
+    Ok(if accept.contains("application/json") {
+        ListingFormat::Json
+    } else if accept.contains("text/plain") && !accept.contains("text/html") {
+        ListingFormat::Text
+    } else {
+        ListingFormat::Html
+    })
+}
+
+// Maps the `?sort=` and `?order=` listing params onto a Drive `orderBy`
+// value, defaulting to name ascending. Whatever the order, folders come
+// ahead of files. Returns None for unrecognized values.
+fn order_by(sort: Option<&str>, order: Option<&str>) -> Option<String> {
+    let key = match sort.unwrap_or("name") {
+        "name" => "name_natural",
+        "modified" => "modifiedTime",
+        "size" => "quotaBytesUsed",
+        _ => return None,
+    };
+    match order.unwrap_or("asc") {
+        "asc" => Some(format!("folder,{}", key)),
+        "desc" => Some(format!("folder,{} desc", key)),
+        _ => None,
+    }
+}
+
+// The listing order for a request. `?sort=` and `?order=` fall back to
+// DEFAULT_SORT and DEFAULT_ORDER, and a bad default to name ascending, so a
+// typo in the config doesn't break every listing.
+fn listing_order_by(config: &Config, url: &Url) -> Option<String> {
+    let (mut default_sort, mut default_order) = (config.default_sort.as_deref(), config.default_order.as_deref());
+    if order_by(default_sort, default_order).is_none() {
+        console_warn!("Ignoring invalid DEFAULT_SORT or DEFAULT_ORDER");
+        (default_sort, default_order) = (None, None);
+    }
+    let sort = query_param(url, "sort");
+    let order = query_param(url, "order");
+    order_by(sort.as_deref().or(default_sort), order.as_deref().or(default_order))
+}
+
+// The `?modifiedAfter=` and `?modifiedBefore=` bounds on a listing, kept as
+// UTC RFC 3339 times ready to compare against Drive's modifiedTime
+#[derive(Clone, Default)]
+struct ModifiedRange {
+    after: Option<String>,
+    before: Option<String>,
+}
+
+impl ModifiedRange {
+    // Reads the bounds from the query. Each takes an RFC 3339 time or a bare
+    // date, which means midnight UTC; anything else is an error naming the
+    // parameter.
+    fn from_url(url: &Url) -> std::result::Result<Self, &'static str> {
+        let bound = |name: &'static str| match query_param(url, name) {
+            Some(value) => parse_modified_bound(&value).map(Some).ok_or(name),
+            None => Ok(None),
+        };
+        Ok(ModifiedRange { after: bound("modifiedAfter")?, before: bound("modifiedBefore")? })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.after.is_none() && self.before.is_none()
+    }
+
+    // The files.list clauses for the bounds, both exclusive
+    fn clauses(&self) -> Vec<String> {
+        let mut clauses = Vec::new();
+        if let Some(after) = &self.after {
+            clauses.push(predicate_clause("modifiedTime >", after));
+        }
+        if let Some(before) = &self.before {
+            clauses.push(predicate_clause("modifiedTime <", before));
+        }
+        clauses
+    }
+
+    // Added to a listing's cache key so each range is cached on its own.
+    // It stays under the folder's prefix, which is what purges go by.
+    fn cache_suffix(&self) -> String {
+        if self.is_empty() {
+            return String::new();
+        }
+        format!(":{}:{}", self.after.as_deref().unwrap_or_default(), self.before.as_deref().unwrap_or_default())
+    }
+
+    // Describes the bounds for a listing's heading, like "modified after
+    // 2024-01-01 and before 2024-02-01". Times at midnight show as dates.
+    fn describe(&self) -> String {
+        let short = |time: &str| time.strip_suffix("T00:00:00Z").unwrap_or(time).to_string();
+        let bounds: Vec<String> = [("after", &self.after), ("before", &self.before)]
+            .into_iter()
+            .filter_map(|(label, bound)| bound.as_deref().map(|time| format!("{} {}", label, short(time))))
+            .collect();
+        format!("modified {}", bounds.join(" and "))
+    }
+}
+
+fn parse_modified_bound(value: &str) -> Option<String> {
+    let time = match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(time) => time.with_timezone(&chrono::Utc),
+        Err(_) => chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0)?.and_utc(),
+    };
+    Some(time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+// The heading of a folder listing, noting any date filter on it
+fn listing_heading(range: &ModifiedRange) -> String {
+    if range.is_empty() {
+        String::from("Files in Drive Folder")
+    } else {
+        format!("Files in Drive Folder {}", range.describe())
+    }
+}
+
+// Name of the file served in place of a folder's listing, like a static web host
+const INDEX_FILE_NAME: &str = "index.html";
+
+async fn list_files(auth: &impl DriveClient, config: &Config, folder_id: &str, root_path: &str, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    let url = req.url()?;
+    let order_by = match listing_order_by(config, &url) {
+        Some(order_by) => order_by,
+        None => return Response::error("Invalid sort or order parameter", 400),
+    };
+    let range = match ModifiedRange::from_url(&url) {
+        Ok(range) => range,
+        Err(name) => return Response::error(format!("Invalid {} parameter, expected an RFC 3339 date or time", name), 400),
+    };
+    
+    // `?page=`/`?per=` switch to one page of the listing at a time
+    let page = query_param(&url, "page");
+    let per = query_param(&url, "per");
+    if page.is_some() || per.is_some() {
+        let page = match page.as_deref().map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(page)) if page >= 1 => page,
+            _ => return Response::error("Invalid page parameter", 400),
+        };
+        let per = match per.as_deref().map(str::parse::<usize>) {
+            None => DEFAULT_PAGE_SIZE,
+            Some(Ok(per)) if (1..=MAX_PAGE_SIZE).contains(&per) => per,
+            _ => return Response::error(format!("Page size must be between 1 and {}", MAX_PAGE_SIZE), 400),
+        };
+        return list_files_page(auth, config, folder_id, root_path, &order_by, &range, page, per, req, ctx).await;
+    }
+    
+    // Listings are cached per folder, sort order and date range;
+    // `?refresh=1` skips the cached copy so fresh uploads show up right away.
+    // A listing past its fresh window is still served, but refreshed in the
+    // background so the next request sees the update.
+    let cache_key = format!("listing:{}:{}{}", folder_id, order_by, range.cache_suffix());
+    let cached = if is_truthy_param(&url, "refresh") {
+        None
+    } else {
+        cached_json::<CachedListing>(config, &cache_key).await
+    };
+    
+    let cached = match cached {
+        Some(cached) => {
+            if now_secs().saturating_sub(cached.fetched_at) >= config.listing_ttl {
+                refresh_listing(auth, config, ctx, folder_id, &order_by, &range, cache_key);
+            }
+            cached
+        }
+        None => {
+            let listing = match fetch_listing(auth, config, folder_id, &order_by, &range).await {
+                Ok(listing) => listing,
+                Err(err) => {
+                    console_error!("Failed to list folder {}: {}", folder_id, err);
+                    return err.into_response("Failed to fetch files from Google Drive");
+                }
+            };
+            store_slugs(config, ctx, &listing.files);
+            let cached = CachedListing { fetched_at: now_secs(), files: listing.files, incomplete_search: listing.incomplete_search };
+            store_json(config, ctx, cache_key, &cached, config.listing_ttl + config.listing_stale_ttl)?;
+            cached
+        }
+    };
+    let incomplete_search = cached.incomplete_search;
+    let files = cached.files;
+    // Filtered after the cache so a config change applies straight away
+    let files = pinned_first(config, without_hidden(&url, visible_files(config, files)));
+    let format = listing_format(req)?;
+    
+    // Go through the name lookup rather than serving the listed entry directly,
+    // since listings don't carry the metadata needed for shortcuts and validators
+    if format == ListingFormat::Html && config.serve_index_html && files.iter().any(|file| file.name == INDEX_FILE_NAME && file.mime_type != FOLDER_MIME_TYPE) {
+        return serve_file_by_name(auth, config, folder_id, INDEX_FILE_NAME, None, req, ctx).await;
+    }
+    
+    let etag = listing_etag(&files, format, &url);
+    if let Some(if_none_match) = req.headers().get("If-None-Match")? {
+        if etag_matches(&if_none_match, &etag) {
+            let headers = Headers::new();
+            headers.set("ETag", &etag)?;
+            headers.set("Cache-Control", &format!("public, max-age={}", config.listing_cache_max_age))?;
+            return Ok(Response::empty()?.with_headers(headers).with_status(304));
+        }
+    }
+    
+    if format == ListingFormat::Json {
+        let json = serde_json::to_vec(&DriveResponse { files, next_page_token: None, incomplete_search })?;
+        let mut response = listing_response(config, req, json, "application/json")?;
+        response.headers_mut().set("ETag", &etag)?;
+        return Ok(response);
+    }
+    if format == ListingFormat::Text {
+        let text = render_text_listing(&files);
+        let mut response = listing_response(config, req, text.into_bytes(), "text/plain; charset=utf-8")?;
+        response.headers_mut().set("ETag", &etag)?;
+        return Ok(response);
+    }
     
-    let body = download_response.bytes().await?;
+    // Links are built relative to the folder being listed, which is always
+    // the slash-terminated request path
+    let mut body = render_breadcrumbs(root_path, url.path());
+    if incomplete_search {
+        body.push_str(&render_incomplete_notice());
+    }
+    if files.is_empty() {
+        let message = if range.is_empty() { "This folder is empty." } else { "No files in this folder match the filter." };
+        body.push_str(&format!("    <p class=\"empty\">{}</p>\n", message));
+    } else {
+        body.push_str(&render_summary(&files));
+        if let Some(name) = query_param(&url, "preview") {
+            body.push_str(&preview_panel(auth, config, &files, &name).await);
+        }
+        body.push_str(&listing_rows(config, &files, root_path, &url));
+    }
+    let html = render_page(config, page_theme(req)?, &listing_heading(&range), &body);
+    let mut response = listing_response(config, req, html.into_bytes(), "text/html; charset=utf-8")?;
+    response.headers_mut().set("ETag", &etag)?;
+    Ok(response)
+}
+
+// Renders the `?preview=` panel for one of the listed files. Only text files
+// up to PREVIEW_MAX_BYTES are fetched; anything else, or a failed fetch,
+// gets a note instead, so the listing itself still renders.
+async fn preview_panel(auth: &impl DriveClient, config: &Config, files: &[DriveFile], name: &str) -> String {
+    let Some(file) = files.iter().find(|file| file.name == name && file.mime_type != FOLDER_MIME_TYPE) else {
+        return render_preview(name, None);
+    };
+    let content_type = config.mime_override(&file.name).unwrap_or_else(|| mime::content_type(&file.mime_type, &file.name));
+    let size = file.size.as_deref().and_then(|size| size.parse::<u64>().ok());
+    if !mime::is_text(content_type) || size.is_none_or(|size| size > config.preview_max_bytes) {
+        return render_preview(&file.name, None);
+    }
     
-    Ok(Response::from_bytes(body)?.with_headers(headers))
+    let bytes = async {
+        let mut response = auth.download(config, &file.id, None, Headers::new(), None).await?;
+        Ok::<Vec<u8>, UpstreamError>(response.bytes().await?)
+    }.await;
+    match bytes {
+        Ok(bytes) => render_preview(&file.name, Some(&String::from_utf8_lossy(&bytes))),
+        Err(err) => {
+            console_warn!("Failed to fetch preview of {}: {}", file.id, err);
+            render_preview(&file.name, None)
+        }
+    }
+}
+
+// Whether `?group=type` asks for a listing split up by kind of file
+fn is_grouped(url: &Url) -> bool {
+    query_param(url, "group").as_deref() == Some("type")
+}
+
+// Rows link from the folder's own path, except for the root folder's
+// listing served at `/` with ROOT_PATH=listing, which links from the root's
+// canonical path since nothing below `/` itself leads anywhere
+fn listing_rows(config: &Config, files: &[DriveFile], root_path: &str, url: &Url) -> String {
+    let base_path = if url.path().starts_with(root_path) { url.path() } else { root_path };
+    if is_grouped(url) {
+        render_grouped_file_rows(config, files, base_path, has_short_links(config))
+    } else {
+        render_file_rows(config, files, base_path, has_short_links(config))
+    }
+}
+
+// An entity tag for a listing that only changes when its entries do: it's a
+// hash over each entry's ID, name, size and modification time, plus the
+// format (HTML, JSON or text) the listing is rendered in and, for HTML, the
+// query string, since parameters like `?group=`, `?theme=` and `?preview=`
+// change the markup
+fn listing_etag(files: &[DriveFile], format: ListingFormat, url: &Url) -> String {
+    let mut hash = Fnv1a::default();
+    hash.write(match format {
+        ListingFormat::Html => b"html",
+        ListingFormat::Json => b"json",
+        ListingFormat::Text => b"text",
+    });
+    if format == ListingFormat::Html {
+        hash.write(url.query().unwrap_or_default().as_bytes());
+        hash.write(&[0]);
+    }
+    for file in files {
+        for field in [Some(&file.id), Some(&file.name), file.size.as_ref(), file.modified_time.as_ref()] {
+            hash.write(field.map_or(&b""[..], |field| field.as_bytes()));
+            // Separates fields so that moving bytes between them changes the hash
+            hash.write(&[0]);
+        }
+    }
+    format!("\"l-{:016x}\"", hash.0)
+}
+
+// 64-bit FNV-1a, which unlike std's hashers has a fixed, documented output,
+// so tags stay stable between deploys
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+// Digits of the `/s/` slugs
+const BASE62_DIGITS: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// KV keys mapping `/s/` slugs to file IDs start with this
+const SLUG_KEY_PREFIX: &str = "slug:";
+
+/// The slug of a file's `/s/` short link: its ID hashed to 64 bits and
+/// written in base62, which comes to at most 11 characters.
+pub(crate) fn short_slug(file_id: &str) -> String {
+    let mut hash = Fnv1a::default();
+    hash.write(file_id.as_bytes());
+    let mut value = hash.0;
+    let mut slug = String::new();
+    loop {
+        slug.push(BASE62_DIGITS[(value % 62) as usize] as char);
+        value /= 62;
+        if value == 0 {
+            break;
+        }
+    }
+    slug
+}
+
+// Whether listings can show short links, which needs somewhere to keep them
+fn has_short_links(config: &Config) -> bool {
+    config.short_links && config.kv.is_some()
+}
+
+// The files in a listing that get short links
+fn slugged_file_ids(files: &[DriveFile]) -> Vec<String> {
+    files.iter().filter(|file| file.mime_type != FOLDER_MIME_TYPE).map(|file| file.id.clone()).collect()
+}
+
+// Stores the slug of any listed file that doesn't have one yet once the
+// response has gone out, so the short links in the listing resolve
+fn store_slugs(config: &Config, ctx: &Context, files: &[DriveFile]) {
+    if !has_short_links(config) {
+        return;
+    }
+    if let Some(kv) = config.kv.clone() {
+        ctx.wait_until(put_slugs(kv, slugged_file_ids(files)));
+    }
+}
+
+// Slugs never change for a file, so they're written without an expiration,
+// and only the first time. Listings that are fetched on every view, like
+// paged ones, would otherwise rewrite every slug each time, running through
+// KV's write quota and its limit of one write per second to a key.
+async fn put_slugs(kv: KvStore, file_ids: Vec<String>) {
+    for batch in file_ids.chunks(MAX_CONCURRENT_LOOKUPS) {
+        future::join_all(batch.iter().map(|file_id| {
+            let key = format!("{}{}", SLUG_KEY_PREFIX, short_slug(file_id));
+            let kv = &kv;
+            async move {
+                if let Ok(Some(stored)) = kv.get(&key).text().await {
+                    if stored == *file_id {
+                        return;
+                    }
+                }
+                let result = match kv.put(&key, file_id.as_str()) {
+                    Ok(put) => put.execute().await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    console_warn!("Failed to store {}: {:?}", key, err);
+                }
+            }
+        }))
+        .await;
+    }
+}
+
+// Serves a file from its `/s/<slug>` short link. The slug is looked up in
+// KV, where listings left it, then served like an `/id/` link.
+async fn serve_file_by_slug(auth: &impl DriveClient, config: &Config, roots: &Roots, slug: &str, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    let Some(kv) = config.kv.as_ref() else {
+        return Response::error("File not found", 404);
+    };
+    let file_id = match kv.get(&format!("{}{}", SLUG_KEY_PREFIX, slug)).text().await {
+        Ok(file_id) => file_id,
+        Err(err) => {
+            console_warn!("Failed to read slug {}: {:?}", slug, err);
+            None
+        }
+    };
+    match file_id {
+        Some(file_id) => serve_file_by_drive_id(auth, config, roots, &file_id, req, ctx).await,
+        None => Response::error("File not found", 404),
+    }
+}
+
+// Entries per page when `?page=` is given without `?per=`
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+// Largest page Drive will return from files.list
+const MAX_PAGE_SIZE: usize = 1000;
+
+// Renders a single page of a folder, fetched straight from Drive rather than
+// from the KV cache. Next links carry Drive's page token so moving forward
+// costs one request; other pages have to walk the tokens from the start.
+#[allow(clippy::too_many_arguments)]
+async fn list_files_page(auth: &impl DriveClient, config: &Config, folder_id: &str, root_path: &str, order_by: &str, range: &ModifiedRange, page: usize, per: usize, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    let url = req.url()?;
+    let query = build_query_from_clauses(config, folder_id, range.clauses());
+    let token = query_param(&url, "token");
+    let result = query_files_page(auth, config, &query, order_by, page, per, token.as_deref()).await;
+    let mut listing = match result {
+        Ok(listing) => listing,
+        Err(err) => {
+            console_error!("Failed to list folder {}: {}", folder_id, err);
+            return err.into_response("Failed to fetch files from Google Drive");
+        }
+    };
+    
+    resolve_listed_shortcuts(auth, config, &mut listing.files).await;
+    listing.files = without_hidden(&url, visible_files(config, listing.files));
+    store_slugs(config, ctx, &listing.files);
+    
+    if wants_json(req)? {
+        let json = serde_json::to_vec(&listing)?;
+        return listing_response(config, req, json, "application/json");
+    }
+    
+    let prev_href = (page > 1).then(|| page_link(&url, page - 1, per, None));
+    let next_href = listing.next_page_token.as_deref().map(|token| page_link(&url, page + 1, per, Some(token)));
+    
+    let mut body = render_breadcrumbs(root_path, url.path());
+    if listing.incomplete_search {
+        body.push_str(&render_incomplete_notice());
+    }
+    body.push_str(&render_pagination((page - 1) * per, listing.files.len(), prev_href.as_deref(), next_href.as_deref()));
+    body.push_str(&listing_rows(config, &listing.files, root_path, &url));
+    let html = render_page(config, page_theme(req)?, &listing_heading(range), &body);
+    listing_response(config, req, html.into_bytes(), "text/html; charset=utf-8")
+}
+
+// Builds a link to another page of the current listing, keeping any other
+// query parameters such as the sort order
+fn page_link(url: &Url, page: usize, per: usize, token: Option<&str>) -> String {
+    let mut link = url.clone();
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !matches!(name.as_ref(), "page" | "per" | "token"))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    {
+        let mut pairs = link.query_pairs_mut();
+        pairs.clear().extend_pairs(kept);
+        pairs.append_pair("page", &page.to_string()).append_pair("per", &per.to_string());
+        if let Some(token) = token {
+            pairs.append_pair("token", token);
+        }
+    }
+    format!("?{}", link.query().unwrap_or_default())
+}
+
+// Most shortcut targets that get looked up at once for a listing
+const MAX_CONCURRENT_LOOKUPS: usize = 8;
+
+// Fills in listed shortcuts with their targets' type, size and links, so the
+// listing shows what each one really is. The lookups run a batch at a time.
+// Shortcuts keep their own name and ID, since that's what links resolve by,
+// and shortcuts to folders are left alone because the router only walks
+// real folders.
+async fn resolve_listed_shortcuts(auth: &impl DriveClient, config: &Config, files: &mut [DriveFile]) {
+    let shortcuts: Vec<(usize, String)> = files
+        .iter()
+        .enumerate()
+        .filter_map(|(index, file)| file.shortcut_details.as_ref().map(|details| (index, details.target_id.clone())))
+        .collect();
+    
+    for batch in shortcuts.chunks(MAX_CONCURRENT_LOOKUPS) {
+        let targets = future::join_all(batch.iter().map(|(_, target_id)| fetch_file_info(auth, config, target_id))).await;
+        for ((index, target_id), target) in batch.iter().zip(targets) {
+            let target = match target {
+                Ok(target) if target.mime_type != FOLDER_MIME_TYPE => target,
+                Ok(_) => continue,
+                Err(err) => {
+                    console_warn!("Failed to resolve shortcut target {}: {}", target_id, err);
+                    continue;
+                }
+            };
+            let file = &mut files[*index];
+            file.mime_type = target.mime_type;
+            file.size = target.size;
+            file.modified_time = target.modified_time;
+            file.md5_checksum = target.md5_checksum;
+            file.thumbnail_link = target.thumbnail_link;
+            file.web_view_link = target.web_view_link;
+            file.web_content_link = target.web_content_link;
+        }
+    }
+}
+
+// Drops files whose extension isn't allowed to be served, so listings don't
+// link to anything that would only answer 403, along with `.access` files,
+// which are never served at all
+fn visible_files(config: &Config, files: Vec<DriveFile>) -> Vec<DriveFile> {
+    files
+        .into_iter()
+        .filter(|file| file.name != access::ACCESS_FILE_NAME)
+        .filter(|file| file.mime_type == FOLDER_MIME_TYPE || config.is_extension_allowed(&file.name))
+        .collect()
+}
+
+// Hoists the files named in PINNED_FILES (by name or ID) to the front of a
+// listing, in the order they're given there, with the rest keeping the
+// listing's sort. Pins that match nothing are skipped. Paged listings come
+// straight from Drive a page at a time, so they aren't reordered.
+fn pinned_first(config: &Config, mut files: Vec<DriveFile>) -> Vec<DriveFile> {
+    let mut pinned = Vec::new();
+    for pin in &config.pinned_files {
+        if let Some(index) = files.iter().position(|file| file.id == *pin || file.name == *pin) {
+            pinned.push(files.remove(index));
+        }
+    }
+    pinned.extend(files);
+    pinned
+}
+
+// Names starting with `.` or `_` are left out of listings unless `?all=1`
+// asks for them, so config and template files can live alongside the rest.
+// They're still served when asked for by name.
+fn without_hidden(url: &Url, mut files: Vec<DriveFile>) -> Vec<DriveFile> {
+    if !is_truthy_param(url, "all") {
+        files.retain(|file| !is_hidden(file));
+    }
+    files
+}
+
+fn is_hidden(file: &DriveFile) -> bool {
+    file.name.starts_with(['.', '_'])
+}
+
+// A generated listing, with the caching policy configured for listings
+fn listing_response(config: &Config, req: &Request, body: Vec<u8>, content_type: &str) -> worker::Result<Response> {
+    let mut response = generated_response(config, req, body, content_type)?;
+    response.headers_mut().set("Cache-Control", &format!("public, max-age={}", config.listing_cache_max_age))?;
+    Ok(response)
+}
+
+// A folder listing as kept in KV, stamped with when it was fetched so stale
+// copies can be told apart from fresh ones
+#[derive(Serialize, Deserialize)]
+struct CachedListing {
+    fetched_at: u64,
+    files: Vec<DriveFile>,
+    #[serde(default)]
+    incomplete_search: bool,
+}
+
+fn now_secs() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+// Fetches a complete folder listing from Drive, limited to `range`, with
+// shortcuts resolved
+async fn fetch_listing(auth: &impl DriveClient, config: &Config, folder_id: &str, order_by: &str, range: &ModifiedRange) -> std::result::Result<DriveResponse, UpstreamError> {
+    let query = build_query_from_clauses(config, folder_id, range.clauses());
+    let mut listing = query_listing(auth, config, &query, Some(order_by)).await?;
+    resolve_listed_shortcuts(auth, config, &mut listing.files).await;
+    Ok(listing)
+}
+
+// Replaces a stale cached listing once the response has gone out. Failures
+// only mean the stale copy is served a little longer.
+fn refresh_listing(auth: &impl DriveClient, config: &Config, ctx: &Context, folder_id: &str, order_by: &str, range: &ModifiedRange, cache_key: String) {
+    let Some(kv) = config.kv.clone() else {
+        return;
+    };
+    let auth = auth.clone();
+    let config = config.clone();
+    let folder_id = folder_id.to_string();
+    let order_by = order_by.to_string();
+    let range = range.clone();
+    ctx.wait_until(async move {
+        let listing = match fetch_listing(&auth, &config, &folder_id, &order_by, &range).await {
+            Ok(listing) => listing,
+            Err(err) => {
+                console_warn!("Failed to refresh listing of {}: {}", folder_id, err);
+                return;
+            }
+        };
+        if has_short_links(&config) {
+            put_slugs(kv.clone(), slugged_file_ids(&listing.files)).await;
+        }
+        let cached = CachedListing { fetched_at: now_secs(), files: listing.files, incomplete_search: listing.incomplete_search };
+        match serde_json::to_string(&cached) {
+            Ok(json) => put_json(kv, cache_key, json, config.listing_ttl + config.listing_stale_ttl).await,
+            Err(err) => console_warn!("Failed to serialize listing of {}: {}", folder_id, err),
+        }
+    });
+}
+
+// Looks up a value cached in KV by an earlier request, treating KV failures
+// as a miss
+async fn cached_json<T: serde::de::DeserializeOwned>(config: &Config, cache_key: &str) -> Option<T> {
+    let kv = config.kv.as_ref()?;
+    match kv.get(cache_key).json::<T>().await {
+        Ok(value) => value,
+        Err(err) => {
+            console_warn!("Failed to read cached {}: {:?}", cache_key, err);
+            None
+        }
+    }
+}
+
+// Writes a value to KV in the background so the response isn't held up
+fn store_json<T: Serialize + ?Sized>(config: &Config, ctx: &Context, cache_key: String, value: &T, ttl: u64) -> worker::Result<()> {
+    let kv = match &config.kv {
+        Some(kv) => kv.clone(),
+        None => return Ok(()),
+    };
+    let json = serde_json::to_string(value)?;
+    ctx.wait_until(put_json(kv, cache_key, json, ttl));
+    Ok(())
+}
+
+async fn put_json(kv: KvStore, cache_key: String, json: String, ttl: u64) {
+    let result = match kv.put(&cache_key, json) {
+        Ok(put) => put.expiration_ttl(ttl).execute().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        console_warn!("Failed to cache {}: {:?}", cache_key, err);
+    }
+}
+
+// Builds the response for a page or document rendered by the worker itself,
+// compressed when the client supports it
+fn generated_response(config: &Config, req: &Request, body: Vec<u8>, content_type: &str) -> worker::Result<Response> {
+    let body_len = body.len() as u64;
+    let headers = Headers::new();
+    headers.set("Content-Type", content_type)?;
+    let response = Response::from_bytes(body)?.with_headers(headers);
+    compress::apply(req, response, content_type, Some(body_len), config.compression_min_bytes)
+}
+
+async fn search_files(auth: &impl DriveClient, config: &Config, folder_id: &str, root_path: &str, req: &Request) -> worker::Result<Response> {
+    let search_text = query_param(&req.url()?, "q").unwrap_or_default();
+    let search_text = search_text.trim();
+    if search_text.is_empty() {
+        return Response::error("Missing search query", 400);
+    }
+    
+    // Results are the root folder's own files, so its `.access` is the only
+    // one that applies
+    if let Some(response) = access::check(auth, config, &[folder_id.to_string()], req).await? {
+        return Ok(response);
+    }
+    
+    // Drive rejects orderBy on fullText queries, so results come back in
+    // relevance order
+    let query = build_query(config, folder_id, &[("fullText contains", search_text)]);
+    let results = match query_listing(auth, config, &query, None).await {
+        Ok(results) => results,
+        Err(err) => {
+            console_error!("Failed to search folder {}: {}", folder_id, err);
+            return err.into_response("Failed to search Google Drive");
+        }
+    };
+    let files = visible_files(config, results.files);
+    
+    let heading = format!("Search results for \"{}\"", search_text);
+    let mut body = if results.incomplete_search { render_incomplete_notice() } else { String::new() };
+    body.push_str(&if files.is_empty() {
+        format!(r#"
+    <p class="empty">No files matched your search. <a href="{}">Browse all files</a></p>
+"#, render::html_escape(root_path))
+    } else {
+        // Search is scoped to the root folder, so results link from there
+        render_file_rows(config, &files, root_path, false)
+    });
+    let html = render_page(config, page_theme(req)?, &heading, &body);
+    generated_response(config, req, html.into_bytes(), "text/html; charset=utf-8")
+}
+
+// Whether a `?type=` entry looks like a MIME type (`application/pdf`) or the
+// start of one (`image/`)
+fn is_valid_type_filter(mime_type: &str) -> bool {
+    let is_token = |part: &str| part.bytes().all(|c| c.is_ascii_alphanumeric() || b"!#$&^_.+-".contains(&c));
+    match mime_type.split_once('/') {
+        Some((major, minor)) => !major.is_empty() && is_token(major) && is_token(minor),
+        None => false,
+    }
+}
+
+// Lists a folder as JSON, narrowed by `?type=` to one or more
+// comma-separated MIME types. An entry ending in a slash matches every type
+// under it, so `?type=image/,application/pdf` gets images and PDFs.
+async fn api_list(auth: &impl DriveClient, config: &Config, folder_id: &str, req: &Request) -> worker::Result<Response> {
+    let url = req.url()?;
+    let Some(order_by) = listing_order_by(config, &url) else {
+        return drive::json_error(config, "Invalid sort or order parameter", 400);
+    };
+    
+    let types = query_param(&url, "type").unwrap_or_default();
+    let types: Vec<&str> = types.split(',').map(str::trim).filter(|mime_type| !mime_type.is_empty()).collect();
+    if let Some(invalid) = types.iter().find(|mime_type| !is_valid_type_filter(mime_type)) {
+        return drive::json_error(config, &format!("Invalid type filter '{}'", invalid), 400);
+    }
+    
+    let mut clauses = Vec::new();
+    if !types.is_empty() {
+        let alternatives: Vec<String> = types
+            .iter()
+            .map(|mime_type| {
+                let field = if mime_type.ends_with('/') { "mimeType contains" } else { "mimeType =" };
+                predicate_clause(field, mime_type)
+            })
+            .collect();
+        clauses.push(format!("({})", alternatives.join(" or ")));
+    }
+    let query = build_query_from_clauses(config, folder_id, clauses);
+    let DriveResponse { mut files, incomplete_search, .. } = match query_listing(auth, config, &query, Some(&order_by)).await {
+        Ok(listing) => listing,
+        Err(err) => {
+            console_error!("Failed to list folder {}: {}", folder_id, err);
+            return err.into_json_response(config, "Failed to fetch files from Google Drive");
+        }
+    };
+    // `contains` matches anywhere in the type, so prefixes are checked
+    // properly here
+    files.retain(|file| {
+        types.is_empty() || types.iter().any(|mime_type| {
+            if mime_type.ends_with('/') { file.mime_type.starts_with(mime_type) } else { file.mime_type == *mime_type }
+        })
+    });
+    let files = visible_files(config, files);
+    
+    let json = serde_json::to_vec(&DriveResponse { files, next_page_token: None, incomplete_search })?;
+    listing_response(config, req, json, "application/json")
+}
+
+// Deepest `?depth=` a folder tree can be asked for, and the most folders it
+// will take in, since every level costs a request per folder
+const MAX_TREE_DEPTH: usize = 5;
+const MAX_TREE_FOLDERS: usize = 500;
+
+// A folder in the tree `/folders/` returns. `folders` is left out below the
+// requested depth, where the subfolders weren't looked at.
+#[derive(Serialize)]
+struct FolderNode {
+    id: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    folders: Option<Vec<FolderNode>>,
+}
+
+// Lists a folder's subfolders as JSON for navigation, one level down unless
+// `?depth=` asks for more. The tree is fetched a level at a time, and a
+// folder seen before is left out so a cycle can't repeat forever.
+async fn list_folders(auth: &impl DriveClient, config: &Config, folder_id: &str, req: &Request) -> worker::Result<Response> {
+    let url = req.url()?;
+    let depth = match query_param(&url, "depth").as_deref().map(str::parse::<usize>) {
+        None => 1,
+        Some(Ok(depth)) if (1..=MAX_TREE_DEPTH).contains(&depth) => depth,
+        _ => return drive::json_error(config, &format!("Depth must be between 1 and {}", MAX_TREE_DEPTH), 400),
+    };
+    
+    let mut children: HashMap<String, Vec<DriveFile>> = HashMap::new();
+    let mut seen = HashSet::from([folder_id.to_string()]);
+    let mut level = vec![folder_id.to_string()];
+    for remaining in (0..depth).rev() {
+        let mut next_level = Vec::new();
+        for batch in level.chunks(MAX_CONCURRENT_LOOKUPS) {
+            let results = future::join_all(batch.iter().map(|parent_id| child_folders(auth, config, parent_id))).await;
+            for (parent_id, result) in batch.iter().zip(results) {
+                let folders = match result {
+                    Ok(folders) => folders,
+                    Err(err) => {
+                        console_error!("Failed to list subfolders of {}: {}", parent_id, err);
+                        return err.into_json_response(config, "Failed to fetch folders from Google Drive");
+                    }
+                };
+                let folders: Vec<DriveFile> = without_hidden(&url, folders)
+                    .into_iter()
+                    .filter(|folder| seen.len() < MAX_TREE_FOLDERS && seen.insert(folder.id.clone()))
+                    .collect();
+                // Private subfolders are still named, as they are in the
+                // listing, but only walked into for requests they let in.
+                // The last level isn't walked, so it isn't checked either.
+                if remaining > 0 {
+                    let allowed = future::join_all(folders.iter().map(|folder| access::is_allowed(auth, config, &folder.id, req))).await;
+                    for (folder, allowed) in folders.iter().zip(allowed) {
+                        match allowed {
+                            Ok(true) => next_level.push(folder.id.clone()),
+                            Ok(false) => {}
+                            Err(err) => {
+                                console_error!("Failed to check access to {}: {}", folder.id, err);
+                                return err.into_json_response(config, "Failed to check folder access");
+                            }
+                        }
+                    }
+                }
+                children.insert(parent_id.clone(), folders);
+            }
+        }
+        level = next_level;
+    }
+    if seen.len() >= MAX_TREE_FOLDERS {
+        console_warn!("Folder tree of {} truncated at {} folders", folder_id, MAX_TREE_FOLDERS);
+    }
+    
+    let json = serde_json::to_vec(&serde_json::json!({ "folders": folder_tree(&mut children, folder_id) }))?;
+    listing_response(config, req, json, "application/json")
+}
+
+async fn child_folders(auth: &impl DriveClient, config: &Config, parent_id: &str) -> std::result::Result<Vec<DriveFile>, UpstreamError> {
+    let query = build_query(config, parent_id, &[("mimeType =", FOLDER_MIME_TYPE)]);
+    query_files(auth, config, &query, Some("name_natural")).await
+}
+
+// Assembles the fetched levels into nested nodes under `parent_id`
+fn folder_tree(children: &mut HashMap<String, Vec<DriveFile>>, parent_id: &str) -> Vec<FolderNode> {
+    let folders = children.remove(parent_id).unwrap_or_default();
+    folders
+        .into_iter()
+        .map(|folder| {
+            let subfolders = children.contains_key(&folder.id).then(|| folder_tree(children, &folder.id));
+            FolderNode { id: folder.id, name: folder.name, folders: subfolders }
+        })
+        .collect()
+}
+
+// Runs a Drive files.list query (as built by `build_query`), following
+// nextPageToken until Drive stops returning one. Pages hold LIST_PAGE_SIZE
+// entries, so larger ones mean fewer round trips.
+async fn query_files(auth: &impl DriveClient, config: &Config, query: &str, order_by: Option<&str>) -> std::result::Result<Vec<DriveFile>, UpstreamError> {
+    Ok(query_listing(auth, config, query, order_by).await?.files)
+}
+
+// Like `query_files`, but also reports whether Drive flagged any page as an
+// incomplete search, for callers that tell the user about it
+async fn query_listing(auth: &impl DriveClient, config: &Config, query: &str, order_by: Option<&str>) -> std::result::Result<DriveResponse, UpstreamError> {
+    let mut params = format!(
+        "{}&pageSize={}&fields=nextPageToken,incompleteSearch,files(id,name,mimeType,webViewLink,webContentLink,shortcutDetails,size,modifiedTime)",
+        query, config.list_page_size
+    );
+    if let Some(order_by) = order_by {
+        params.push_str(&format!("&orderBy={}", urlencoding::encode(order_by)));
+    }
+    
+    let mut files: Vec<DriveFile> = Vec::new();
+    let mut incomplete_search = false;
+    let mut page_token: Option<String> = None;
+    for _ in 0..MAX_LIST_PAGES {
+        let page_params = match &page_token {
+            Some(token) => format!("{}&pageToken={}", params, urlencoding::encode(token)),
+            None => params.clone(),
+        };
+        
+        let drive_response: DriveResponse = auth.list(config, &page_params).await?;
+        files.extend(drive_response.files);
+        incomplete_search |= drive_response.incomplete_search;
+        
+        page_token = drive_response.next_page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+    
+    if page_token.is_some() {
+        console_warn!("Folder listing truncated after {} pages", MAX_LIST_PAGES);
+    }
+    if incomplete_search {
+        console_warn!("Drive reported an incomplete search for {}", query);
+    }
+    
+    Ok(DriveResponse { files, next_page_token: None, incomplete_search })
+}
+
+// Fetches page `page` of a files.list query. With the page's token in hand
+// that's a single request; otherwise the tokens of the earlier pages are
+// fetched first. A page past the end comes back empty.
+async fn query_files_page(auth: &impl DriveClient, config: &Config, query: &str, order_by: &str, page: usize, per: usize, token: Option<&str>) -> std::result::Result<DriveResponse, UpstreamError> {
+    let params = format!("{}&orderBy={}&pageSize={}", query, urlencoding::encode(order_by), per);
+    let empty = DriveResponse { files: Vec::new(), next_page_token: None, incomplete_search: false };
+    
+    let mut page_token = token.map(str::to_string);
+    if page_token.is_none() && page > 1 {
+        if page > MAX_LIST_PAGES {
+            return Ok(empty);
+        }
+        // Skipped pages only need their token, not their files
+        for _ in 1..page {
+            let page_params = match &page_token {
+                Some(token) => format!("{}&fields=nextPageToken&pageToken={}", params, urlencoding::encode(token)),
+                None => format!("{}&fields=nextPageToken", params),
+            };
+            let skipped: PageToken = auth.list(config, &page_params).await?;
+            match skipped.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(empty),
+            }
+        }
+    }
+    
+    let mut page_params = format!(
+        "{}&fields=nextPageToken,incompleteSearch,files(id,name,mimeType,webViewLink,webContentLink,shortcutDetails,size,modifiedTime)",
+        params
+    );
+    if let Some(token) = &page_token {
+        page_params.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+    }
+    auth.list(config, &page_params).await
+}
+
+// Outcome of looking a file up by name, which Drive doesn't require to be unique
+enum NameLookup {
+    Found(Box<DriveFile>),
+    NotFound,
+    // Several files share the name and nothing picked one out
+    Ambiguous(Vec<DriveFile>),
+}
+
+// Finds the file called `file_name` in a folder, following shortcuts to their
+// target so callers always get the real file's metadata. `file_id` selects
+// among files that share the name; without it a shared name is reported as
+// ambiguous rather than guessed at.
+async fn resolve_file_by_name(auth: &impl DriveClient, config: &Config, folder_id: &str, file_name: &str, file_id: Option<&str>) -> std::result::Result<NameLookup, UpstreamError> {
+    lookup_by_name(auth, config, folder_id, ("name =", file_name), file_id).await
+}
+
+// Like `resolve_file_by_name`, but for any file whose name starts with
+// `prefix`, ignoring case. Drive's `contains` only matches name prefixes.
+async fn resolve_file_by_name_prefix(auth: &impl DriveClient, config: &Config, folder_id: &str, prefix: &str, file_id: Option<&str>) -> std::result::Result<NameLookup, UpstreamError> {
+    lookup_by_name(auth, config, folder_id, ("name contains", prefix), file_id).await
+}
+
+async fn lookup_by_name(auth: &impl DriveClient, config: &Config, folder_id: &str, predicate: (&str, &str), file_id: Option<&str>) -> std::result::Result<NameLookup, UpstreamError> {
+    // First, search for the file by name in the specified folder
+    let query = build_query(config, folder_id, &[predicate]);
+    let search_result: DriveResponse = auth.list(config, &format!("{}&fields=files({})", query, FILE_FIELDS)).await?;
+    
+    // A folder only counts when no file has the name, so files with
+    // folder-like names keep being served as files
+    let mut matches = search_result.files;
+    // `.access` files are never served, so they aren't offered as choices
+    // either, however loosely the name was matched
+    matches.retain(|file| file.name != access::ACCESS_FILE_NAME);
+    if matches.iter().any(|file| file.mime_type != FOLDER_MIME_TYPE) {
+        matches.retain(|file| file.mime_type != FOLDER_MIME_TYPE);
+    }
+    let file_info = match file_id {
+        Some(file_id) => match matches.into_iter().find(|file| file.id == file_id) {
+            Some(file_info) => file_info,
+            None => return Ok(NameLookup::NotFound),
+        },
+        None if matches.len() > 1 => return Ok(NameLookup::Ambiguous(matches)),
+        None => match matches.pop() {
+            Some(file_info) => file_info,
+            None => return Ok(NameLookup::NotFound),
+        },
+    };
+
+    resolve_shortcuts(auth, config, file_info).await.map(|file_info| NameLookup::Found(Box::new(file_info)))
+}
+
+// Lists every file sharing a name so the client can pick one; the links only
+// add `?id=` to the current URL, so this works for any route that looks files
+// up by name
+fn disambiguation_response(config: &Config, file_name: &str, files: &[DriveFile], req: &Request) -> worker::Result<Response> {
+    let heading = format!("Several files are named \"{}\"", file_name);
+    choices_response(config, &heading, files, req)
+}
+
+fn choices_response(config: &Config, heading: &str, files: &[DriveFile], req: &Request) -> worker::Result<Response> {
+    let html = render_page(config, page_theme(req)?, heading, &render_file_rows(config, files, "", false));
+    Ok(generated_response(config, req, html.into_bytes(), "text/html; charset=utf-8")?.with_status(300))
+}
+
+// How many shortcuts we'll follow before assuming they loop back on themselves
+const MAX_SHORTCUT_DEPTH: usize = 5;
+
+// Follows a chain of shortcuts to the file it ultimately points at
+async fn resolve_shortcuts(auth: &impl DriveClient, config: &Config, mut file_info: DriveFile) -> std::result::Result<DriveFile, UpstreamError> {
+    for _ in 0..MAX_SHORTCUT_DEPTH {
+        let target_file_id = match &file_info.shortcut_details {
+            Some(shortcut_details) => &shortcut_details.target_id,
+            None => return Ok(file_info),
+        };
+        console_debug!("File is a shortcut, resolving target ID: {}", target_file_id);
+        // FILE_FIELDS includes shortcutDetails, so a target that is itself a
+        // shortcut gets followed on the next pass
+        file_info = fetch_file_info(auth, config, target_file_id).await?;
+    }
+    
+    match file_info.shortcut_details {
+        Some(_) => Err(UpstreamError::ShortcutLoop),
+        None => Ok(file_info),
+    }
+}
+
+async fn serve_file_by_name(auth: &impl DriveClient, config: &Config, folder_id: &str, file_name: &str, file_id: Option<&str>, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    let file_info = match resolve_file_by_name(auth, config, folder_id, file_name, file_id).await {
+        Ok(NameLookup::Found(file_info)) => *file_info,
+        // An exact name always wins, so prefix matching only kicks in for
+        // names that would otherwise 404
+        Ok(NameLookup::NotFound) if wants_prefix_match(config, &req.url()?) => {
+            match resolve_file_by_name_prefix(auth, config, folder_id, file_name, file_id).await {
+                Ok(NameLookup::Found(file_info)) => *file_info,
+                Ok(NameLookup::NotFound) => return Response::error("File not found", 404),
+                Ok(NameLookup::Ambiguous(files)) => {
+                    let heading = format!("Several files start with \"{}\"", file_name);
+                    return choices_response(config, &heading, &files, req);
+                }
+                Err(err) => return err.into_response("Failed to look up file"),
+            }
+        }
+        Ok(NameLookup::NotFound) => return Response::error("File not found", 404),
+        Ok(NameLookup::Ambiguous(files)) => return disambiguation_response(config, file_name, &files, req),
+        Err(err) => return err.into_response("Failed to look up file"),
+    };
+    if file_info.name == access::ACCESS_FILE_NAME {
+        return Response::error("File not found", 404);
+    }
+    
+    // A folder asked for without its trailing slash gets sent to the
+    // canonical URL, so relative links in its listing resolve correctly
+    if file_info.mime_type == FOLDER_MIME_TYPE {
+        let mut url = external_url(config, req)?;
+        url.set_path(&format!("{}/", url.path()));
+        return Response::redirect_with_status(url, 301);
+    }
+    
+    // `?view=1` hands the file off to Drive's own viewer, which is the better
+    // experience for docs and spreadsheets than an exported download
+    if is_truthy_param(&req.url()?, "view") {
+        config.log.record_file(&file_info.id);
+        return match &file_info.web_view_link {
+            Some(link) => Response::redirect_with_status(Url::parse(link)?, 302),
+            None => Response::error("No Drive viewer available for this file", 404),
+        };
+    }
+    
+    // `?via=drive` sends the client to Drive's own download link, saving the
+    // worker the bandwidth. That link only works for files anyone can open,
+    // so everything else is still proxied.
+    if query_param(&req.url()?, "via").as_deref() == Some("drive") {
+        let is_public = file_info
+            .permission_ids
+            .as_ref()
+            .is_some_and(|ids| ids.iter().any(|id| PUBLIC_PERMISSION_IDS.contains(&id.as_str())));
+        if let Some(link) = file_info.web_content_link.as_ref().filter(|_| is_public) {
+            config.log.record_file(&file_info.id);
+            return Response::redirect_with_status(Url::parse(link)?, 302);
+        }
+    }
+    
+    serve_file_by_id(auth, config, &file_info, Disposition::Negotiated, req, ctx).await
+}
+
+// `?match=prefix` or `?match=exact` picks how names are matched for one
+// request, overriding NAME_MATCH
+fn wants_prefix_match(config: &Config, url: &Url) -> bool {
+    match query_param(url, "match").as_deref() {
+        Some("prefix") => true,
+        Some("exact") => false,
+        _ => config.prefix_name_match,
+    }
+}
+
+// Answers with a file's metadata as JSON instead of its content. Errors are
+// JSON too, so API clients only ever have one shape to handle.
+async fn serve_metadata(auth: &impl DriveClient, config: &Config, folder_id: &str, file_name: &str, req: &Request) -> worker::Result<Response> {
+    let file_id = query_param(&req.url()?, "id");
+    let file_info = match resolve_file_by_name(auth, config, folder_id, file_name, file_id.as_deref()).await {
+        Ok(NameLookup::Found(file_info)) if file_info.name != access::ACCESS_FILE_NAME => file_info,
+        Ok(NameLookup::Found(_)) | Ok(NameLookup::NotFound) => return drive::json_error(config, "File not found", 404),
+        Ok(NameLookup::Ambiguous(files)) => {
+            let mut body = drive::error_envelope(config, "Several files share this name; pick one with ?id=", 300);
+            body["files"] = serde_json::to_value(&files)?;
+            return Ok(Response::from_json(&body)?.with_status(300));
+        }
+        Err(err) => return err.into_json_response(config, "Failed to look up file"),
+    };
+    config.log.record_file(&file_info.id);
+    
+    let json = serde_json::to_vec(&file_info)?;
+    generated_response(config, req, json, "application/json")
+}
+
+// Drive IDs are URL-safe base64-ish strings, so anything else can't be one
+fn is_valid_file_id(file_id: &str) -> bool {
+    !file_id.is_empty() && file_id.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+// Serves a file from a stable `/id/<fileId>` link, skipping the name search.
+// The file still has to live somewhere below a root folder, otherwise this
+// would serve anything the credentials can see.
+async fn serve_file_by_drive_id(auth: &impl DriveClient, config: &Config, roots: &Roots, file_id: &str, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    if !is_valid_file_id(file_id) {
+        return Response::error("Invalid file ID", 400);
+    }
+    
+    let lookup = async {
+        let file_info = fetch_file_info(auth, config, file_id).await?;
+        if file_info.name == access::ACCESS_FILE_NAME {
+            return Ok(None);
+        }
+        let Some(folder_ids) = folders_from_root(auth, config, roots, &file_info).await? else {
+            return Ok(None);
+        };
+        resolve_shortcuts(auth, config, file_info).await.map(|file_info| Some((file_info, folder_ids)))
+    }.await;
+    
+    match lookup {
+        Ok(Some((file_info, folder_ids))) if file_info.mime_type != FOLDER_MIME_TYPE => {
+            if let Some(response) = access::check(auth, config, &folder_ids, req).await? {
+                return Ok(response);
+            }
+            serve_file_by_id(auth, config, &file_info, Disposition::Negotiated, req, ctx).await
+        }
+        Ok(_) | Err(UpstreamError::Status { status: 404, .. }) => Response::error("File not found", 404),
+        Err(err) => err.into_response("Failed to look up file"),
+    }
+}
+
+// Fetches a single file's metadata by ID
+async fn fetch_file_info(auth: &impl DriveClient, config: &Config, file_id: &str) -> std::result::Result<DriveFile, UpstreamError> {
+    auth.get_metadata(config, file_id, FILE_FIELDS).await
+}
+
+// Walks up from a file towards the top of its drive, looking for one of the
+// root folders, and returns the folders between it and the file, starting
+// with the root. None means the file isn't below any root. Drive gives every
+// item a single parent, so this is one request per level, and none at all
+// for files directly in a root.
+async fn folders_from_root(auth: &impl DriveClient, config: &Config, roots: &Roots, file_info: &DriveFile) -> std::result::Result<Option<Vec<String>>, UpstreamError> {
+    let root_ids = roots.folder_ids();
+    let mut folder_ids = Vec::new();
+    let mut parent = file_info.parents.as_ref().and_then(|parents| parents.first()).cloned();
+    for _ in 0..MAX_FOLDER_DEPTH {
+        let parent_id = match parent {
+            Some(parent_id) => parent_id,
+            None => return Ok(None),
+        };
+        folder_ids.push(parent_id.clone());
+        if root_ids.contains(&parent_id.as_str()) {
+            folder_ids.reverse();
+            return Ok(Some(folder_ids));
+        }
+        let folder: DriveParents = auth.get_metadata(config, &parent_id, "parents").await?;
+        parent = folder.parents.and_then(|parents| parents.into_iter().next());
+    }
+    Ok(None)
+}
+
+// Most files a single archive will include, to bound the Drive requests
+const MAX_ZIP_FILES: usize = 1000;
+
+// A file headed for an archive, under its path within it
+struct ZipFile {
+    path: String,
+    id: String,
+    modified_time: Option<String>,
+}
+
+// Makes a Drive name safe to use as one segment of an archive path, so that
+// names containing slashes or dot segments can't escape the archive's folder
+// on extraction
+fn zip_segment(name: &str) -> String {
+    match name {
+        "." | ".." => String::from("_"),
+        name => name.replace(['/', '\\'], "_"),
+    }
+}
+
+// Streams a folder as a ZIP archive. Everything is listed up front so that
+// listing errors and oversized folders get a proper error response; the file
+// contents are then fetched one at a time as the client reads, so memory use
+// stays flat. Subfolders are only included with `?recursive=1`, and
+// Google-native files are skipped since they have no bytes of their own.
+async fn serve_zip(auth: &impl DriveClient, config: &Config, env: &Env, folder_id: &str, folder_name: &str, req: &Request) -> worker::Result<Response> {
+    let recursive = is_truthy_param(&req.url()?, "recursive");
+    
+    let mut files = Vec::new();
+    let mut total_size = 0u64;
+    let mut pending = std::collections::VecDeque::from([(folder_id.to_string(), String::new(), 0)]);
+    while let Some((folder_id, prefix, depth)) = pending.pop_front() {
+        // The folder itself was checked on the way in; subfolders the
+        // request can't see are left out rather than failing the archive
+        if depth > 0 {
+            match access::is_allowed(auth, config, &folder_id, req).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    console_error!("Failed to check access to {} for archive: {}", folder_id, err);
+                    return err.into_response("Failed to check folder access");
+                }
+            }
+        }
+        let listed = match query_files(auth, config, &build_query(config, &folder_id, &[]), Some("name_natural")).await {
+            Ok(listed) => listed,
+            Err(err) => {
+                console_error!("Failed to list folder {} for archive: {}", folder_id, err);
+                return err.into_response("Failed to fetch files from Google Drive");
+            }
+        };
+        for file in visible_files(config, listed) {
+            let path = format!("{}{}", prefix, zip_segment(&file.name));
+            if file.mime_type == FOLDER_MIME_TYPE {
+                if recursive && depth + 1 < MAX_FOLDER_DEPTH {
+                    pending.push_back((file.id, format!("{}/", path), depth + 1));
+                }
+                continue;
+            }
+            if file.mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX) {
+                continue;
+            }
+            if files.len() >= MAX_ZIP_FILES {
+                return Response::error(format!("Folder has more than {} files to archive", MAX_ZIP_FILES), 413);
+            }
+            let size = file.size.as_deref().and_then(|size| size.parse::<u64>().ok()).unwrap_or(0);
+            total_size += size + zip::entry_overhead(&path);
+            files.push(ZipFile { path, id: file.id, modified_time: file.modified_time });
+        }
+    }
+    if total_size > zip::MAX_ARCHIVE_SIZE {
+        return Response::error("Folder is too large to archive", 413);
+    }
+    
+    // The stream outlives this request's borrows, so it gets its own copies
+    let state = ZipState {
+        auth: auth.clone(),
+        config: Config::from_env(env),
+        files: files.into(),
+        writer: zip::ZipWriter::default(),
+        current: None,
+        done: false,
+    };
+    let body = stream::unfold(state, next_zip_chunk);
+    
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/zip")?;
+    headers.set("Content-Disposition", &content_disposition("attachment", &format!("{}.zip", folder_name)))?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::from_stream(body)?.with_headers(headers))
+}
+
+struct ZipState<A> {
+    auth: A,
+    config: Config,
+    files: std::collections::VecDeque<ZipFile>,
+    writer: zip::ZipWriter,
+    // Body of the file currently being copied into the archive
+    current: Option<std::pin::Pin<Box<ByteStream>>>,
+    done: bool,
+}
+
+// Produces the next piece of the archive: a file's header, a chunk of its
+// data or its descriptor, and finally the central directory. A failure part
+// way through can only cut the archive short, since the response has
+// already started.
+async fn next_zip_chunk<A: DriveClient>(mut state: ZipState<A>) -> Option<(worker::Result<Vec<u8>>, ZipState<A>)> {
+    if let Some(current) = &mut state.current {
+        return match current.next().await {
+            Some(Ok(chunk)) => {
+                state.writer.write(&chunk);
+                Some((Ok(chunk), state))
+            }
+            Some(Err(err)) => {
+                state.done = true;
+                state.current = None;
+                Some((Err(err), state))
+            }
+            None => {
+                state.current = None;
+                let descriptor = state.writer.finish_entry();
+                Some((Ok(descriptor), state))
+            }
+        };
+    }
+    if state.done {
+        return None;
+    }
+    
+    let file = match state.files.pop_front() {
+        Some(file) => file,
+        None => {
+            state.done = true;
+            let directory = state.writer.finish();
+            return Some((Ok(directory), state));
+        }
+    };
+    let body = match state.auth.download(&state.config, &file.id, None, Headers::new(), None).await {
+        Ok(mut response) => response.stream(),
+        Err(err) => Err(Error::from(err.to_string())),
+    };
+    match body {
+        Ok(body) => {
+            state.current = Some(Box::pin(body));
+            let header = state.writer.start_entry(&file.path, file.modified_time.as_deref());
+            Some((Ok(header), state))
+        }
+        Err(err) => {
+            console_error!("Failed to add {} to archive: {}", file.path, err);
+            state.done = true;
+            Some((Err(err), state))
+        }
+    }
+}
+
+// Thumbnails are derived from file content and only change along with it
+const THUMBNAIL_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+// Largest thumbnail edge length we'll ask Drive to render
+const MAX_THUMBNAIL_SIZE: u32 = 1600;
+
+// Drive thumbnail links end in a size directive like `=s220`; rewrite it (or
+// add one) to request a different longest edge
+fn resize_thumbnail_link(link: &str, size: u32) -> String {
+    if let Some(index) = link.rfind("=s") {
+        if link[index + 2..].chars().all(|c| c.is_ascii_digit()) {
+            return format!("{}=s{}", &link[..index], size);
+        }
+    }
+    format!("{}=s{}", link, size)
+}
+
+async fn serve_thumbnail(auth: &impl DriveClient, config: &Config, folder_id: &str, file_name: &str, req: &Request) -> worker::Result<Response> {
+    let size = match query_param(&req.url()?, "size") {
+        Some(size) => match size.parse::<u32>() {
+            Ok(size) if (1..=MAX_THUMBNAIL_SIZE).contains(&size) => Some(size),
+            _ => return Response::error(format!("Thumbnail size must be between 1 and {}", MAX_THUMBNAIL_SIZE), 400),
+        },
+        None => None,
+    };
+    
+    let file_id = query_param(&req.url()?, "id");
+    let file_info = match resolve_file_by_name(auth, config, folder_id, file_name, file_id.as_deref()).await {
+        Ok(NameLookup::Found(file_info)) if file_info.name != access::ACCESS_FILE_NAME => *file_info,
+        Ok(NameLookup::Found(_)) | Ok(NameLookup::NotFound) => return Response::error("File not found", 404),
+        Ok(NameLookup::Ambiguous(files)) => return disambiguation_response(config, file_name, &files, req),
+        Err(err) => return err.into_response("Failed to look up file"),
+    };
+    config.log.record_file(&file_info.id);
+    
+    let thumbnail_link = match &file_info.thumbnail_link {
+        Some(link) => link,
+        None => return Response::error("No thumbnail available for this file", 404),
+    };
+    let thumbnail_url = match size {
+        Some(size) => resize_thumbnail_link(thumbnail_link, size),
+        None => thumbnail_link.clone(),
+    };
+    
+    match proxy_thumbnail(&thumbnail_url, THUMBNAIL_MAX_AGE).await? {
+        Some(response) => Ok(response),
+        None => Response::error("Failed to fetch thumbnail", 502),
+    }
+}
+
+// Thumbnail links are pre-authorized URLs on Google's image servers, so
+// they're fetched without Drive credentials. None when the image server
+// didn't hand one back.
+async fn proxy_thumbnail(thumbnail_url: &str, max_age: u64) -> worker::Result<Option<Response>> {
+    let thumbnail_request = Request::new(thumbnail_url, Method::Get)?;
+    let thumbnail_response = Fetch::Request(thumbnail_request).send().await?;
+    let thumbnail_status = thumbnail_response.status_code();
+    if !(200..300).contains(&thumbnail_status) {
+        return Ok(None);
+    }
+    
+    let headers = Headers::new();
+    let content_type = thumbnail_response.headers().get("Content-Type")?;
+    headers.set("Content-Type", content_type.as_deref().unwrap_or("image/png"))?;
+    headers.set("Cache-Control", &format!("public, max-age={}", max_age))?;
+    
+    let (_, body) = thumbnail_response.into_parts();
+    Ok(Some(Response::from_body(body)?.with_headers(headers)))
+}
+
+// PDFs are rarely edited once published, so their previews are kept much
+// longer than ordinary thumbnails
+const PREVIEW_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+const PDF_MIME_TYPE: &str = "application/pdf";
+
+// Longest edge of a preview when `?size=` isn't given, large enough to read
+// a first page at
+const DEFAULT_PREVIEW_SIZE: u32 = 1200;
+
+// Serves an image of a PDF's first page, rendered by Drive as a large
+// thumbnail, so a document can be previewed without downloading it. Anything
+// that isn't a PDF, or that Drive has no rendering for, is a 404.
+async fn serve_preview(auth: &impl DriveClient, config: &Config, folder_id: &str, file_name: &str, req: &Request) -> worker::Result<Response> {
+    let size = match query_param(&req.url()?, "size") {
+        Some(size) => match size.parse::<u32>() {
+            Ok(size) if (1..=MAX_THUMBNAIL_SIZE).contains(&size) => size,
+            _ => return Response::error(format!("Preview size must be between 1 and {}", MAX_THUMBNAIL_SIZE), 400),
+        },
+        None => DEFAULT_PREVIEW_SIZE,
+    };
+    
+    let file_id = query_param(&req.url()?, "id");
+    let file_info = match resolve_file_by_name(auth, config, folder_id, file_name, file_id.as_deref()).await {
+        Ok(NameLookup::Found(file_info)) if file_info.name != access::ACCESS_FILE_NAME => *file_info,
+        Ok(NameLookup::Found(_)) | Ok(NameLookup::NotFound) => return Response::error("File not found", 404),
+        Ok(NameLookup::Ambiguous(files)) => return disambiguation_response(config, file_name, &files, req),
+        Err(err) => return err.into_response("Failed to look up file"),
+    };
+    if file_info.mime_type != PDF_MIME_TYPE {
+        return Response::error("Previews are only available for PDFs", 404);
+    }
+    config.log.record_file(&file_info.id);
+    
+    let preview = match &file_info.thumbnail_link {
+        Some(link) => proxy_thumbnail(&resize_thumbnail_link(link, size), PREVIEW_MAX_AGE).await?,
+        None => None,
+    };
+    match preview {
+        Some(response) => Ok(response),
+        None => Response::error("No preview available for this file", 404),
+    }
+}
+
+// Prefix shared by all Google-native (Docs, Sheets, Slides, ...) MIME types
+pub(crate) const GOOGLE_APPS_MIME_PREFIX: &str = "application/vnd.google-apps.";
+
+// Export formats Drive supports for each Google-native type, as pairs of the
+// short name accepted by `?export=` and the target MIME type. The first entry
+// is the default when no format is requested.
+fn export_formats(mime_type: &str) -> &'static [(&'static str, &'static str)] {
+    match mime_type {
+        "application/vnd.google-apps.document" => &[
+            ("pdf", "application/pdf"),
+            ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+            ("odt", "application/vnd.oasis.opendocument.text"),
+            ("rtf", "application/rtf"),
+            ("txt", "text/plain"),
+            ("html", "text/html"),
+            ("epub", "application/epub+zip"),
+        ],
+        "application/vnd.google-apps.spreadsheet" => &[
+            ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+            ("pdf", "application/pdf"),
+            ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+            ("csv", "text/csv"),
+            ("tsv", "text/tab-separated-values"),
+        ],
+        "application/vnd.google-apps.presentation" => &[
+            ("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+            ("pdf", "application/pdf"),
+            ("odp", "application/vnd.oasis.opendocument.presentation"),
+            ("txt", "text/plain"),
+        ],
+        "application/vnd.google-apps.drawing" => &[
+            ("pdf", "application/pdf"),
+            ("png", "image/png"),
+            ("jpeg", "image/jpeg"),
+            ("svg", "image/svg+xml"),
+        ],
+        _ => &[],
+    }
+}
+
+// Picks the export format that best fits an Accept header, trying media
+// ranges in order of preference. A wildcard, or no header at all, gets the
+// default format; None means the client only listed types we can't produce.
+fn negotiate_export<'a>(formats: &'a [(&'static str, &'static str)], accept: Option<&str>) -> Option<Option<&'a (&'static str, &'static str)>> {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty() && !formats.is_empty()) else {
+        return Some(formats.first());
+    };
+    let mut ranges: Vec<(String, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let media_range = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!media_range.is_empty() && quality > 0.0).then_some((media_range, quality))
+        })
+        .collect();
+    // Stable, so ranges of equal quality keep the client's order
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    
+    for (media_range, _) in &ranges {
+        let format = match media_range.as_str() {
+            "*/*" => return Some(formats.first()),
+            range => match range.strip_suffix("/*") {
+                Some(major) => formats.iter().find(|(_, mime_type)| mime_type.split('/').next() == Some(major)),
+                None => formats.iter().find(|(_, mime_type)| *mime_type == range),
+            },
+        };
+        if format.is_some() {
+            return Some(format);
+        }
+    }
+    None
+}
+
+// Converts a Drive RFC 3339 timestamp into the IMF-fixdate form used by HTTP headers
+fn http_date(rfc3339: &str) -> Option<String> {
+    let time = chrono::DateTime::parse_from_rfc3339(rfc3339).ok()?;
+    Some(time.with_timezone(&chrono::Utc).format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+}
+
+// Whether an If-None-Match header value matches the given (strong) ETag,
+// using the weak comparison that RFC 9110 prescribes for GET and HEAD
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+// A body's MD5 as lowercase hex, the form of Drive's md5Checksum. The
+// Workers runtime's SubtleCrypto takes MD5, unlike browsers'.
+async fn md5_hex(bytes: &[u8]) -> worker::Result<String> {
+    let crypto: web_sys::Crypto = js_sys::Reflect::get(&js_sys::global(), &"crypto".into())?.unchecked_into();
+    let digest = JsFuture::from(crypto.subtle().digest_with_str_and_u8_array("MD5", bytes)?).await?;
+    Ok(js_sys::Uint8Array::new(&digest).to_vec().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Chunks of a download read ahead of the client. Drive's chunks are tens of
+// kilobytes, so a slow client ties up at most a few hundred kilobytes in
+// memory.
+const STREAM_READ_AHEAD: usize = 4;
+
+// Relays a download to the client, reading at most STREAM_READ_AHEAD chunks
+// ahead of it to smooth over Drive's pauses. Nothing more is read until the
+// client takes a chunk, so a slow client slows the download instead of
+// buffering it.
+fn with_backpressure<S: futures_util::Stream + Unpin>(upstream: S) -> ReadAhead<S> {
+    ReadAhead {
+        upstream: upstream.fuse(),
+        queue: std::collections::VecDeque::with_capacity(STREAM_READ_AHEAD),
+    }
+}
+
+struct ReadAhead<S: futures_util::Stream> {
+    upstream: stream::Fuse<S>,
+    queue: std::collections::VecDeque<S::Item>,
+}
+
+impl<S: futures_util::Stream + Unpin> futures_util::Stream for ReadAhead<S>
+where
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        while this.queue.len() < STREAM_READ_AHEAD {
+            match this.upstream.poll_next_unpin(cx) {
+                std::task::Poll::Ready(Some(chunk)) => this.queue.push_back(chunk),
+                _ => break,
+            }
+        }
+        match this.queue.pop_front() {
+            Some(chunk) => std::task::Poll::Ready(Some(chunk)),
+            None if futures_util::stream::FusedStream::is_terminated(&this.upstream) => std::task::Poll::Ready(None),
+            None => std::task::Poll::Pending,
+        }
+    }
+}
+
+// How a Range header applies to a file
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum ByteRange {
+    // Serve everything, as for a header we don't understand
+    Full,
+    // The Range value to send Drive
+    Partial(String),
+    // Starts past the end of the file
+    Unsatisfiable,
+}
+
+// Reads a Range header against the file's size, resolving suffix
+// (`bytes=-500`) and open-ended (`bytes=500-`) ranges to explicit offsets.
+// Multiple ranges would need a multipart response, so like malformed headers
+// they're ignored in favour of the full content, as RFC 9110 allows. Without
+// a known size a well-formed range is passed along as given.
+fn parse_range(range: &str, size: Option<u64>) -> ByteRange {
+    let Some((unit, spec)) = range.trim().split_once('=') else {
+        return ByteRange::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    // Plain digits only; `parse` would also take a sign
+    let parse = |value: &str| if value.bytes().all(|c| c.is_ascii_digit()) { value.parse::<u64>().ok() } else { None };
+    
+    let (start, end) = match (first, last) {
+        ("", "") => return ByteRange::Full,
+        ("", suffix) => {
+            let Some(suffix) = parse(suffix) else {
+                return ByteRange::Full;
+            };
+            match size {
+                Some(size) if suffix > 0 && size > 0 => (size.saturating_sub(suffix), size - 1),
+                None if suffix > 0 => return ByteRange::Partial(format!("bytes=-{}", suffix)),
+                _ => return ByteRange::Unsatisfiable,
+            }
+        }
+        (first, last) => {
+            let Some(start) = parse(first) else {
+                return ByteRange::Full;
+            };
+            let end = match last {
+                "" => None,
+                last => match parse(last) {
+                    Some(end) if end >= start => Some(end),
+                    _ => return ByteRange::Full,
+                },
+            };
+            match size {
+                Some(size) if start >= size => return ByteRange::Unsatisfiable,
+                Some(size) => (start, end.map_or(size - 1, |end| end.min(size - 1))),
+                None => {
+                    let end = end.map(|end| end.to_string()).unwrap_or_default();
+                    return ByteRange::Partial(format!("bytes={}-{}", start, end));
+                }
+            }
+        }
+    };
+    ByteRange::Partial(format!("bytes={}-{}", start, end))
+}
+
+// If-Range holds either an entity tag or a date, and either has to match the
+// current file exactly. Weak tags never match, since the bytes of a range have
+// to line up with what the client already has.
+fn if_range_matches(if_range: &str, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with("W/") {
+        false
+    } else if if_range.starts_with('"') {
+        etag == Some(if_range)
+    } else {
+        last_modified == Some(if_range)
+    }
+}
+
+// Whether a resource last modified at `modified_time` (RFC 3339) is unchanged
+// since the HTTP date given in an If-Modified-Since header
+fn not_modified_since(if_modified_since: &str, modified_time: &str) -> bool {
+    let since = match chrono::DateTime::parse_from_rfc2822(if_modified_since) {
+        Ok(since) => since,
+        Err(_) => return false,
+    };
+    match chrono::DateTime::parse_from_rfc3339(modified_time) {
+        // HTTP dates only have second precision
+        Ok(modified) => modified.timestamp() <= since.timestamp(),
+        Err(_) => false,
+    }
+}
+
+// The name an export is saved under. Google-native files have no extension
+// of their own, so the export format's is added, unless the name already
+// ends with it. Any other dot in the name is left alone.
+fn export_file_name(name: &str, format_name: &str) -> String {
+    let has_extension = name
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension.eq_ignore_ascii_case(format_name));
+    if has_extension {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, format_name)
+    }
+}
+
+// Builds a Content-Disposition value with both a plain ASCII `filename` for
+// old clients and an RFC 5987 `filename*` that preserves the real name
+fn content_disposition(disposition: &str, file_name: &str) -> String {
+    let ascii_name: String = file_name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, ascii_name, urlencoding::encode(file_name)
+    )
+}
+
+// The request URL as the client saw it. Behind one of TRUSTED_PROXIES that
+// means taking the scheme and host from its X-Forwarded-Proto and
+// X-Forwarded-Host, so absolute links point at the public hostname rather
+// than the worker's own. Anyone else's forwarded headers are ignored.
+fn external_url(config: &Config, req: &Request) -> worker::Result<Url> {
+    let mut url = req.url()?;
+    let client_ip = req.headers().get("CF-Connecting-IP")?;
+    if !client_ip.is_some_and(|ip| config.trusted_proxies.contains(&ip)) {
+        return Ok(url);
+    }
+    
+    // Each proxy along the way may have appended its own value, and the
+    // first is the one the client connected to
+    let first = |value: Option<String>| value.and_then(|value| value.split(',').next().map(|part| part.trim().to_string()));
+    let scheme = match first(req.headers().get("X-Forwarded-Proto")?).as_deref() {
+        Some("http") => "http",
+        Some("https") => "https",
+        _ => url.scheme(),
+    }
+    .to_string();
+    let host = first(req.headers().get("X-Forwarded-Host")?).unwrap_or_else(|| {
+        let host = url.host_str().unwrap_or_default();
+        url.port().map_or_else(|| host.to_string(), |port| format!("{}:{}", host, port))
+    });
+    
+    // Parsing the pair on its own keeps anything that isn't a plain host and
+    // port out of the URL
+    match Url::parse(&format!("{}://{}/", scheme, host)) {
+        Ok(forwarded) if forwarded.path() == "/" && forwarded.username().is_empty() && forwarded.host_str().is_some() => {
+            // Only fails when the scheme can't change, which http and https can
+            let _ = url.set_scheme(&scheme);
+            url.set_host(forwarded.host_str())?;
+            let _ = url.set_port(forwarded.port());
+        }
+        _ => console_warn!("Ignoring invalid X-Forwarded-Host {}", host),
+    }
+    Ok(url)
+}
+
+// The colour scheme asked for with `?theme=light` or `?theme=dark`, which
+// otherwise follows the system setting
+fn page_theme(req: &Request) -> worker::Result<Theme> {
+    Ok(Theme::from_param(query_param(&req.url()?, "theme").as_deref()))
+}
+
+// Whether a flag-style query param like `?download=1` is switched on
+fn is_truthy_param(url: &Url, name: &str) -> bool {
+    matches!(query_param(url, name).as_deref(), Some("1" | "true" | "yes"))
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+// Largest file (in bytes) copied into the edge cache as it's served. Bigger
+// ones always come from Drive.
+const MAX_CACHE_FILL_BYTES: u64 = 8 * 1024 * 1024;
+
+// Every disposition a file can be cached under, None being `/raw/`
+const CACHED_DISPOSITIONS: [Option<&str>; 3] = [Some("inline"), Some("attachment"), None];
+
+// Edge cache key for a served file (or one export format of it), on the
+// request's own origin. Keying on the content's version means an update in
+// Drive is served straight away instead of once the old copy expires. The
+// version is Drive's checksum, or the modification time for Google-native
+// files, which have no checksum.
+fn file_cache_key(url: &Url, file_info: &DriveFile, format_name: Option<&str>, disposition: Option<&str>) -> String {
+    let mut cache_key = url.clone();
+    match format_name {
+        Some(format_name) => cache_key.set_path(&format!("/files/.cache/{}.{}", file_info.id, format_name)),
+        None => cache_key.set_path(&format!("/files/.cache/{}", file_info.id)),
+    }
+    let version = file_info.md5_checksum.as_deref().or(file_info.modified_time.as_deref()).unwrap_or_default();
+    cache_key
+        .query_pairs_mut()
+        .clear()
+        .append_pair("disposition", disposition.unwrap_or("none"))
+        .append_pair("version", version);
+    cache_key.to_string()
+}
+
+// How a served file's Content-Disposition is picked
+#[derive(Clone, Copy)]
+enum Disposition {
+    // From the request, else the file's type; see `resolve_disposition`
+    Negotiated,
+    // Left out entirely, for raw embedding
+    Omitted,
+}
+
+// Picks between inline and attachment, in order of precedence:
+//  1. `?download=1` (or `?dl=1`) forces a download
+//  2. `?inline=1` forces inline display
+//  3. DISPOSITION_BY_TYPE's entry for the content type
+//  4. inline
+fn resolve_disposition<'a>(config: &'a Config, url: &Url, content_type: &str) -> &'a str {
+    if is_truthy_param(url, "download") || is_truthy_param(url, "dl") {
+        "attachment"
+    } else if is_truthy_param(url, "inline") {
+        "inline"
+    } else {
+        config.disposition_for(content_type)
+    }
+}
+
+async fn serve_file_by_id(auth: &impl DriveClient, config: &Config, file_info: &DriveFile, disposition: Disposition, req: &Request, ctx: &Context) -> worker::Result<Response> {
+    let file_id = &file_info.id;
+    let url = req.url()?;
+    config.log.record_file(file_id);
+    
+    // Checked on the resolved file, since a prefix match or a shortcut can
+    // lead to one without the request naming it
+    if file_info.name == access::ACCESS_FILE_NAME {
+        return Response::error("File not found", 404);
+    }
+    
+    // Checked against the resolved file rather than the requested name, so a
+    // shortcut can't be used to get around it
+    if !config.is_extension_allowed(&file_info.name) {
+        return Response::error("This type of file can't be served", 403);
+    }
+    
+    // Google-native files have no binary content, so they have to go through
+    // the export endpoint in one of the formats Drive can convert them to
+    let export = if file_info.mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX) {
+        let formats = export_formats(&file_info.mime_type);
+        let requested = query_param(&url, "export");
+        let format = match &requested {
+            Some(name) => formats.iter().find(|(format_name, _)| format_name.eq_ignore_ascii_case(name)),
+            // Without `?export=` the Accept header picks the format
+            None => match negotiate_export(formats, req.headers().get("Accept")?.as_deref()) {
+                Some(format) => format,
+                None => {
+                    let offered: Vec<&str> = formats.iter().map(|(_, mime_type)| *mime_type).collect();
+                    let message = format!("Files of type {} can only be exported as {}", file_info.mime_type, offered.join(", "));
+                    return Response::error(message, 406);
+                }
+            },
+        };
+        match format {
+            Some(format) => Some(*format),
+            None => {
+                let message = match requested {
+                    Some(name) => format!("Export format '{}' is not supported for {}", name, file_info.mime_type),
+                    None => format!("Files of type {} can't be exported", file_info.mime_type),
+                };
+                return Response::error(message, 415);
+            }
+        }
+    } else {
+        None
+    };
+    
+    // Validators for conditional requests. Google-native files have no
+    // checksum, so they only get Last-Modified.
+    let etag = file_info.md5_checksum.as_ref().map(|md5| match export {
+        Some((format_name, _)) => format!("\"{}-{}\"", md5, format_name),
+        None => format!("\"{}\"", md5),
+    });
+    let last_modified = file_info.modified_time.as_deref().and_then(http_date);
+    
+    // If-None-Match takes precedence over If-Modified-Since when both are sent
+    let not_modified = match (req.headers().get("If-None-Match")?, &etag) {
+        (Some(if_none_match), Some(etag)) => etag_matches(&if_none_match, etag),
+        (Some(_), None) => false,
+        (None, _) => match (req.headers().get("If-Modified-Since")?, &file_info.modified_time) {
+            (Some(if_modified_since), Some(modified_time)) => not_modified_since(&if_modified_since, modified_time),
+            _ => false,
+        },
+    };
+    let content_type = match export {
+        Some((_, export_mime_type)) => export_mime_type,
+        // Overrides from MIME_TYPES beat whatever Drive or the extension says
+        None => config.mime_override(&file_info.name).unwrap_or_else(|| mime::content_type(&file_info.mime_type, &file_info.name)),
+    };
+    let max_age = match export {
+        Some(_) => config.export_cache_max_age,
+        None => config.max_age_for(content_type),
+    };
+    
+    if not_modified {
+        let headers = Headers::new();
+        if let Some(etag) = &etag {
+            headers.set("ETag", etag)?;
+        }
+        if let Some(last_modified) = &last_modified {
+            headers.set("Last-Modified", last_modified)?;
+        }
+        headers.set("Cache-Control", &format!("public, max-age={}", max_age))?;
+        return Ok(Response::empty()?.with_headers(headers).with_status(304));
+    }
+    
+    // Exports are generated on the fly and don't support byte ranges. A range
+    // conditioned on If-Range only applies while the file is unchanged; once
+    // it's changed the client gets the whole new file instead.
+    let range = match export {
+        Some(_) => None,
+        None => match req.headers().get("If-Range")? {
+            Some(if_range) if !if_range_matches(&if_range, etag.as_deref(), last_modified.as_deref()) => None,
+            _ => req.headers().get("Range")?,
+        },
+    };
+    
+    let disposition = match disposition {
+        Disposition::Negotiated => Some(resolve_disposition(config, &url, content_type)),
+        Disposition::Omitted => None,
+    };
+    
+    // Create response with appropriate headers
+    let headers = Headers::new();
+    headers.set("Content-Type", &mime::with_charset(content_type, &config.default_charset))?;
+    if let Some(disposition) = disposition {
+        let file_name = match export {
+            Some((format_name, _)) => export_file_name(&file_info.name, format_name),
+            None => file_info.name.clone(),
+        };
+        headers.set("Content-Disposition", &content_disposition(disposition, &file_name))?;
+    }
+    headers.set("Cache-Control", &format!("public, max-age={}", max_age))?;
+    if let Some(etag) = &etag {
+        headers.set("ETag", etag)?;
+    }
+    if let Some(last_modified) = &last_modified {
+        headers.set("Last-Modified", last_modified)?;
+    }
+    match export {
+        // The format can depend on Accept, so shared caches have to keep the
+        // variants apart
+        Some(_) => headers.append("Vary", "Accept")?,
+        None => headers.set("Accept-Ranges", "bytes")?,
+    }
+    
+    // Exports are generated on demand, so only stored files have a known size
+    let body_len = match export {
+        Some(_) => None,
+        None => file_info.size.as_deref().and_then(|size| size.parse::<u64>().ok()),
+    };
+    
+    // Drive only ever sees a single range with both ends spelled out;
+    // anything it might misread is answered here or dropped for the whole file
+    let range = match range.map(|range| parse_range(&range, body_len)) {
+        None | Some(ByteRange::Full) => None,
+        Some(ByteRange::Partial(range)) => Some(range),
+        Some(ByteRange::Unsatisfiable) => {
+            let headers = Headers::new();
+            if let Some(body_len) = body_len {
+                headers.set("Content-Range", &format!("bytes */{}", body_len))?;
+            }
+            return Ok(Response::error("Range Not Satisfiable", 416)?.with_headers(headers));
+        }
+    };
+    
+    // HEAD gets the same headers as GET without downloading anything
+    if req.method() == Method::Head {
+        if let Some(body_len) = body_len {
+            headers.set("Content-Length", &body_len.to_string())?;
+        }
+        return Ok(Response::empty()?.with_headers(headers));
+    }
+    
+    // Key the cache on the resolved file ID rather than the requested name, so
+    // shortcuts share their target's entry and renames don't serve stale bytes
+    let cache_key = file_cache_key(&url, file_info, export.map(|(format_name, _)| format_name), disposition);
+    
+    // Partial responses can't be stored in the cache, so ranged requests always
+    // go straight to Drive
+    let cache = Cache::default();
+    if range.is_none() {
+        if let Some(cached) = cache.get(&cache_key, false).await? {
+            console_debug!("Serving file {} from cache", file_id);
+            return compress::apply(req, cached, content_type, body_len, config.compression_min_bytes);
+        }
+    }
+    
+    // Forward any Range header so Drive only sends the requested bytes
+    let download_headers = Headers::new();
+    if let Some(range) = &range {
+        download_headers.set("Range", range)?;
+    }
+    
+    // Tied to the client's connection, so a download nobody is waiting for
+    // stops costing Drive quota
+    let signal = AbortSignal::from(req.inner().signal());
+    
+    // Download (or export) the file content. Whole files known to be small
+    // enough to hold in memory go through the coalescer when it's bound, so a
+    // burst of misses for the same file shares one transfer. It streams the
+    // file on as it arrives, so a lone request isn't held up by it, and only
+    // gives up on Drive once every client reading it has gone. Exports have
+    // no size until they're generated, so they never qualify.
+    let export_mime_type = export.map(|(_, export_mime_type)| export_mime_type);
+    let coalescer = config.coalescer.as_ref().filter(|_| range.is_none() && body_len.is_some_and(|len| len <= coalesce::MAX_COALESCED_BYTES));
+    let download = match coalescer {
+        Some(coalescer) => coalesce::download(coalescer, file_id, &signal).await,
+        None => auth.download(config, file_id, export_mime_type, download_headers, Some(&signal)).await,
+    };
+    let mut download_response = match download {
+        Ok(response) => response,
+        Err(UpstreamError::Cancelled) => {
+            console_log!("Client disconnected, cancelled download of {}", file_id);
+            config.log.record_cancelled();
+            return UpstreamError::Cancelled.into_response("Download cancelled");
+        }
+        Err(err) => return err.into_response("Failed to download file"),
+    };
+    let download_status = download_response.status_code();
+    
+    // Drive answers a satisfiable Range with 206 and its own Content-Range
+    let content_range = download_response.headers().get("Content-Range")?;
+    
+    // The body is streamed rather than buffered, so its length has to come
+    // from Drive's headers. Drive's own Content-Length describes exactly the
+    // bytes being sent (including for ranges), so it wins over the metadata.
+    let upstream_len = download_response.headers().get("Content-Length")?.and_then(|length| length.parse::<u64>().ok());
+    if download_status != 206 {
+        if let (Some(upstream_len), Some(body_len)) = (upstream_len, body_len) {
+            if upstream_len != body_len {
+                console_warn!("File {} is {} bytes but its metadata says {}", file_id, upstream_len, body_len);
+            }
+        }
+    }
+    let body_len = upstream_len.or(if download_status == 206 { None } else { body_len });
+    if let Some(body_len) = body_len {
+        headers.set("Content-Length", &body_len.to_string())?;
+    }
+    
+    // With VERIFY_CHECKSUMS the whole file is read and checked against the
+    // MD5 Drive keeps for it before anything goes out. Exports have no
+    // checksum and ranges can't be checked, so those are streamed as usual.
+    let expected_md5 = file_info.md5_checksum.as_deref().filter(|_| config.verify_checksums && export.is_none() && download_status != 206);
+    let response = match expected_md5 {
+        Some(expected_md5) => {
+            let bytes = download_response.bytes().await?;
+            let actual_md5 = md5_hex(&bytes).await?;
+            if !actual_md5.eq_ignore_ascii_case(expected_md5) {
+                console_error!("File {} failed its checksum: Drive says {}, got {}", file_id, expected_md5, actual_md5);
+                return Response::error("Downloaded file failed its integrity check", 502);
+            }
+            Response::from_bytes(bytes)?
+        }
+        // Otherwise Drive's body stream is relayed at the client's pace
+        None => match download_response.stream() {
+            Ok(stream) => Response::from_stream(with_backpressure(stream))?,
+            Err(_) => download_response,
+        },
+    };
+    
+    if download_status == 206 {
+        if let Some(content_range) = content_range {
+            headers.set("Content-Range", &content_range)?;
+        }
+        return Ok(response.with_headers(headers).with_status(206));
+    }
+    
+    // Fill the cache from a tee of the stream in the background, so the
+    // client doesn't wait for the whole file to be cached first. The tee
+    // holds whatever the cache has read and the client hasn't, which for a
+    // slow client is most of the file, so only small files are teed.
+    let mut response = response.with_headers(headers);
+    if body_len.is_some_and(|len| len <= MAX_CACHE_FILL_BYTES) {
+        let cached_response = response.cloned()?;
+        let file_id = file_id.clone();
+        ctx.wait_until(async move {
+            if let Err(err) = cache.put(&cache_key, cached_response).await {
+                console_warn!("Failed to cache file {}: {}", file_id, err);
+            }
+        });
+    }
+    
+    // The cache holds the identity-encoded body; compression is negotiated
+    // per request on the way out
+    compress::apply(req, response, content_type, body_len, config.compression_min_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::drive::fake::{block_on, FakeDrive};
+
+    fn file(id: &str, name: &str, mime_type: &str) -> serde_json::Value {
+        json!({ "id": id, "name": name, "mimeType": mime_type })
+    }
+
+    fn config() -> Config {
+        Config { list_page_size: 1000, drive_max_attempts: 1, ..Config::default() }
+    }
+
+    fn ok<T>(result: std::result::Result<T, UpstreamError>) -> T {
+        result.unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn found_id(lookup: NameLookup) -> String {
+        match lookup {
+            NameLookup::Found(file) => file.id,
+            NameLookup::NotFound => panic!("expected a file, found nothing"),
+            NameLookup::Ambiguous(_) => panic!("expected a file, found several"),
+        }
+    }
+
+    #[test]
+    fn resolves_a_file_by_exact_name() {
+        let drive = FakeDrive::default()
+            .with_file("root", file("a", "report.pdf", "application/pdf"))
+            .with_file("root", file("b", "report.pdf.bak", "application/octet-stream"))
+            .with_file("elsewhere", file("c", "report.pdf", "application/pdf"));
+        let lookup = block_on(resolve_file_by_name(&drive, &config(), "root", "report.pdf", None));
+        assert_eq!(found_id(ok(lookup)), "a");
+    }
+
+    #[test]
+    fn reports_a_missing_name_as_not_found() {
+        let drive = FakeDrive::default().with_file("root", file("a", "report.pdf", "application/pdf"));
+        let lookup = block_on(resolve_file_by_name(&drive, &config(), "root", "missing.pdf", None));
+        assert!(matches!(lookup, Ok(NameLookup::NotFound)));
+    }
+
+    #[test]
+    fn reports_a_shared_name_as_ambiguous_until_an_id_picks_one() {
+        let drive = FakeDrive::default()
+            .with_file("root", file("a", "notes.txt", "text/plain"))
+            .with_file("root", file("b", "notes.txt", "text/plain"));
+        let lookup = block_on(resolve_file_by_name(&drive, &config(), "root", "notes.txt", None));
+        assert!(matches!(lookup, Ok(NameLookup::Ambiguous(files)) if files.len() == 2));
+
+        let lookup = block_on(resolve_file_by_name(&drive, &config(), "root", "notes.txt", Some("b")));
+        assert_eq!(found_id(ok(lookup)), "b");
+        let lookup = block_on(resolve_file_by_name(&drive, &config(), "root", "notes.txt", Some("c")));
+        assert!(matches!(lookup, Ok(NameLookup::NotFound)));
+    }
+
+    #[test]
+    fn prefers_a_file_over_a_folder_of_the_same_name() {
+        let drive = FakeDrive::default()
+            .with_file("root", file("folder", "photos", FOLDER_MIME_TYPE))
+            .with_file("root", file("file", "photos", "application/zip"));
+        let lookup = block_on(resolve_file_by_name(&drive, &config(), "root", "photos", None));
+        assert_eq!(found_id(ok(lookup)), "file");
+    }
+
+    #[test]
+    fn never_resolves_a_prefix_to_an_access_file() {
+        let drive = FakeDrive::default().with_file("root", file("secret", access::ACCESS_FILE_NAME, "text/plain"));
+        let lookup = block_on(resolve_file_by_name_prefix(&drive, &config(), "root", ".acc", None));
+        assert!(matches!(lookup, Ok(NameLookup::NotFound)));
+    }
+
+    #[test]
+    fn lists_every_page_of_a_folder() {
+        let mut drive = FakeDrive::default();
+        for index in 0..5 {
+            drive = drive.with_file("root", file(&format!("id{}", index), &format!("file{}.txt", index), "text/plain"));
+        }
+        let config = Config { list_page_size: 2, ..config() };
+        let listing = ok(block_on(fetch_listing(&drive, &config, "root", "name_natural", &ModifiedRange::default())));
+        let ids: Vec<&str> = listing.files.iter().map(|file| file.id.as_str()).collect();
+        assert_eq!(ids, ["id0", "id1", "id2", "id3", "id4"]);
+        assert!(!listing.incomplete_search);
+    }
+
+    #[test]
+    fn lists_shortcuts_with_their_targets_details() {
+        let drive = FakeDrive::default()
+            .with_file("root", json!({ "id": "link", "name": "latest.pdf", "mimeType": "application/vnd.google-apps.shortcut", "shortcutDetails": { "targetId": "target" } }))
+            .with_file("archive", json!({ "id": "target", "name": "2024.pdf", "mimeType": "application/pdf", "size": "2048" }));
+        let listing = ok(block_on(fetch_listing(&drive, &config(), "root", "name_natural", &ModifiedRange::default())));
+        assert_eq!(listing.files.len(), 1);
+        let listed = &listing.files[0];
+        assert_eq!((listed.name.as_str(), listed.mime_type.as_str(), listed.size.as_deref()), ("latest.pdf", "application/pdf", Some("2048")));
+    }
+
+    #[test]
+    fn refuses_a_range_starting_past_the_end() {
+        assert_eq!(parse_range("bytes=1000-", Some(1000)), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5000-6000", Some(1000)), ByteRange::Unsatisfiable);
+        // The last byte is still in range
+        assert_eq!(parse_range("bytes=999-", Some(1000)), ByteRange::Partial(String::from("bytes=999-999")));
+    }
+
+    #[test]
+    fn serves_everything_for_a_malformed_range() {
+        for header in ["", "bytes", "bytes=", "bytes=-", "bytes=abc-def", "bytes=+1-5", "bytes=10-5", "items=0-5"] {
+            assert_eq!(parse_range(header, Some(1000)), ByteRange::Full, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn resolves_a_suffix_range_against_the_size() {
+        assert_eq!(parse_range("bytes=-500", Some(1000)), ByteRange::Partial(String::from("bytes=500-999")));
+        // A suffix longer than the file is the whole file
+        assert_eq!(parse_range("bytes=-5000", Some(1000)), ByteRange::Partial(String::from("bytes=0-999")));
+        assert_eq!(parse_range("bytes=-0", Some(1000)), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-500", None), ByteRange::Partial(String::from("bytes=-500")));
+    }
+
+    #[test]
+    fn resolves_an_open_ended_range_against_the_size() {
+        assert_eq!(parse_range("bytes=500-", Some(1000)), ByteRange::Partial(String::from("bytes=500-999")));
+        assert_eq!(parse_range("bytes=500-", None), ByteRange::Partial(String::from("bytes=500-")));
+        // An end past the last byte is clamped to it
+        assert_eq!(parse_range("bytes=0-4999", Some(1000)), ByteRange::Partial(String::from("bytes=0-999")));
+        assert_eq!(parse_range(" Bytes = 10 - 19 ", Some(1000)), ByteRange::Partial(String::from("bytes=10-19")));
+    }
+
+    #[test]
+    fn serves_everything_for_multiple_ranges() {
+        assert_eq!(parse_range("bytes=0-99,200-299", Some(1000)), ByteRange::Full);
+        assert_eq!(parse_range("bytes=0-99, -100", None), ByteRange::Full);
+    }
+
+    #[test]
+    fn serves_everything_for_invalid_ranges() {
+        for header in ["bytes=1-2-3", "bytes=-1-", "bytes=0x10-20", "bytes=1.5-2", "bytes=18446744073709551616-"] {
+            assert_eq!(parse_range(header, Some(1000)), ByteRange::Full, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn decodes_an_encoded_slash_within_a_segment() {
+        assert_eq!(decode_path_segment("a%2Fb").as_deref(), Some("a/b"));
+        assert_eq!(decode_path_segment("a%2fb").as_deref(), Some("a/b"));
+        assert_eq!(decode_path_segment("caf%C3%A9%20menu.pdf").as_deref(), Some("café menu.pdf"));
+    }
+
+    #[test]
+    fn rejects_escapes_that_are_not_utf8() {
+        assert_eq!(decode_path_segment("%FF"), None);
+        assert_eq!(decode_path_segment("caf%C3"), None);
+    }
+
+    #[test]
+    fn keeps_plus_signs_and_stray_percents() {
+        assert_eq!(decode_path_segment("a+b.txt").as_deref(), Some("a+b.txt"));
+        assert_eq!(decode_path_segment("100%.txt").as_deref(), Some("100%.txt"));
+        assert_eq!(decode_path_segment("%zz%4").as_deref(), Some("%zz%4"));
+    }
+
+    #[test]
+    fn rejects_dot_segments() {
+        assert!(validate_segment("..").is_err());
+        assert!(validate_segment(".").is_err());
+        // Only whole segments are relative; dots within names are fine
+        assert!(validate_segment("...").is_ok());
+        assert!(validate_segment("..hidden").is_ok());
+        assert!(validate_segment("archive.tar.gz").is_ok());
+    }
+
+    #[test]
+    fn passes_empty_segments_on_to_find_nothing() {
+        // An empty last segment is a folder listing, so emptiness is valid;
+        // an empty folder name in the middle of a path just matches nothing
+        assert!(validate_segment("").is_ok());
+        let drive = FakeDrive::default().with_file("root", file("folder", "", FOLDER_MIME_TYPE));
+        assert_eq!(ok(block_on(find_child_folder(&drive, &config(), "root", ""))), None);
+    }
+
+    #[test]
+    fn rejects_control_characters_and_separators() {
+        for segment in ["a\0b", "line\nbreak", "tab\there", "bell\u{7}", "del\u{7f}", "next\u{85}line", "a/b"] {
+            assert!(validate_segment(segment).is_err(), "{:?}", segment);
+        }
+    }
+
+    #[test]
+    fn escapes_quotes_and_backslashes_in_query_values() {
+        assert_eq!(quote_query_value("plain.txt"), "'plain.txt'");
+        assert_eq!(quote_query_value("Bob's file"), r"'Bob\'s file'");
+        assert_eq!(quote_query_value(r"C:\temp"), r"'C:\\temp'");
+        // The backslash is escaped before the quote, so the quote's own
+        // escape isn't doubled
+        assert_eq!(quote_query_value(r"\'"), r"'\\\''");
+    }
+
+    #[test]
+    fn builds_queries_that_keep_names_inside_their_quotes() {
+        let query = build_query(&config(), "root", &[("name =", "x' or name contains '")]);
+        let decoded = urlencoding::decode(query.strip_prefix("q=").unwrap()).unwrap();
+        assert_eq!(decoded, r"name = 'x\' or name contains \'' and 'root' in parents");
+
+        // An `and` inside the name stays part of the quoted value
+        let query = build_query(&config(), "root", &[("name =", "x' and name contains 'y")]);
+        let decoded = urlencoding::decode(query.strip_prefix("q=").unwrap()).unwrap();
+        assert_eq!(decoded, r"name = 'x\' and name contains \'y' and 'root' in parents");
+
+        // A raw `+` in a query string reads as a space, so it has to be encoded
+        let query = build_query(&config(), "root", &[("name =", "a+b.txt")]);
+        assert!(query.contains("a%2Bb.txt"), "{}", query);
+        assert!(!query.contains('+'), "{}", query);
+        let decoded = urlencoding::decode(query.strip_prefix("q=").unwrap()).unwrap();
+        assert_eq!(decoded, "name = 'a+b.txt' and 'root' in parents");
+    }
+
+    #[test]
+    fn matches_if_range_against_a_strong_etag() {
+        assert!(if_range_matches("\"abc\"", Some("\"abc\""), None));
+        assert!(if_range_matches(" \"abc\" ", Some("\"abc\""), None));
+        assert!(!if_range_matches("\"abc\"", Some("\"def\""), None));
+        // A tag can't match a file that has none, even if the date would
+        assert!(!if_range_matches("\"abc\"", None, Some("\"abc\"")));
+    }
+
+    #[test]
+    fn never_matches_if_range_with_a_weak_etag() {
+        assert!(!if_range_matches("W/\"abc\"", Some("\"abc\""), None));
+        assert!(!if_range_matches("W/\"abc\"", Some("W/\"abc\""), None));
+    }
+
+    #[test]
+    fn matches_if_range_against_the_exact_date() {
+        let last_modified = Some("Tue, 15 Oct 2024 08:30:00 GMT");
+        assert!(if_range_matches("Tue, 15 Oct 2024 08:30:00 GMT", None, last_modified));
+        assert!(!if_range_matches("Tue, 15 Oct 2024 08:30:01 GMT", None, last_modified));
+        assert!(!if_range_matches("Tue, 15 Oct 2024 08:30:00 GMT", Some("\"abc\""), None));
+    }
+
+    #[test]
+    fn strips_an_exact_path_prefix() {
+        assert_eq!(strip_path_prefix("/docs", "/docs"), Some("/"));
+        assert_eq!(strip_path_prefix("/docs/", "/docs"), Some("/"));
+        assert_eq!(strip_path_prefix("/docs/files/a.txt", "/docs"), Some("/files/a.txt"));
+        // Without a prefix every path is below it
+        assert_eq!(strip_path_prefix("/files/a.txt", ""), Some("/files/a.txt"));
+    }
+
+    #[test]
+    fn only_strips_a_prefix_at_a_segment_boundary() {
+        assert_eq!(strip_path_prefix("/docsfoo", "/docs"), None);
+        assert_eq!(strip_path_prefix("/docsfoo/files/", "/docs"), None);
+        assert_eq!(strip_path_prefix("/other/docs/", "/docs"), None);
+    }
+
+    #[test]
+    fn lists_pinned_files_first_in_their_configured_order() {
+        let config = Config { pinned_files: vec!["c".to_string(), "missing".to_string(), "a.txt".to_string()], ..config() };
+        let files = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| serde_json::from_value(file(&name[..1], name, "text/plain")).unwrap())
+            .collect();
+        let ids: Vec<String> = pinned_first(&config, files).into_iter().map(|file: DriveFile| file.id).collect();
+        assert_eq!(ids, ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn relays_a_large_download_without_running_ahead_of_the_client() {
+        const CHUNK_SIZE: usize = 64 * 1024;
+        const CHUNKS: usize = 1024;
+        let read = std::rc::Rc::new(std::cell::Cell::new(0));
+        let upstream = stream::iter(0..CHUNKS).map({
+            let read = read.clone();
+            move |_| {
+                read.set(read.get() + 1);
+                Ok::<Vec<u8>, Error>(vec![0; CHUNK_SIZE])
+            }
+        });
+
+        let mut relayed = with_backpressure(upstream);
+        let (mut sent, mut bytes) = (0, 0);
+        block_on(async {
+            while let Some(chunk) = relayed.next().await {
+                sent += 1;
+                bytes += chunk.unwrap().len();
+                // Drive is always ready here, so only the relay holds it back
+                assert!(read.get() - sent <= STREAM_READ_AHEAD, "{} chunks buffered", read.get() - sent);
+            }
+        });
+        assert_eq!(bytes, CHUNK_SIZE * CHUNKS);
+        assert_eq!(read.get(), CHUNKS);
+    }
+
+    #[test]
+    fn keys_cached_files_on_their_content_version() {
+        let url = Url::parse("https://example.com/files/report.pdf?download=1").unwrap();
+        let stored = |md5: &str| -> DriveFile { serde_json::from_value(json!({ "id": "a", "name": "report.pdf", "mimeType": "application/pdf", "md5Checksum": md5, "modifiedTime": "2024-01-01T00:00:00Z" })).unwrap() };
+        assert_eq!(file_cache_key(&url, &stored("0123"), None, Some("attachment")), "https://example.com/files/.cache/a?disposition=attachment&version=0123");
+        assert_ne!(file_cache_key(&url, &stored("0123"), None, None), file_cache_key(&url, &stored("4567"), None, None));
+
+        // Google-native files have no checksum, so exports go by modification time
+        let native = |modified: &str| -> DriveFile { serde_json::from_value(json!({ "id": "b", "name": "Notes", "mimeType": "application/vnd.google-apps.document", "modifiedTime": modified })).unwrap() };
+        let key = |modified: &str| file_cache_key(&url, &native(modified), Some("pdf"), Some("inline"));
+        assert_eq!(key("2024-01-01T00:00:00Z"), "https://example.com/files/.cache/b.pdf?disposition=inline&version=2024-01-01T00%3A00%3A00Z");
+        assert_ne!(key("2024-01-01T00:00:00Z"), key("2024-01-02T00:00:00Z"));
+    }
 }
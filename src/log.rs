@@ -0,0 +1,103 @@
+use std::cell::{Cell, RefCell};
+
+use worker::*;
+
+/// How much the worker logs, from `LOG_LEVEL`. Each level includes the ones
+/// before it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(test, derive(Default))]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[cfg_attr(test, default)]
+    Info,
+    Debug,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, ()> {
+        match value.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Details picked up while a request is handled, written out as a single
+/// structured line once the response is ready.
+#[derive(Clone, Default)]
+pub struct RequestLog {
+    request_id: RefCell<Option<String>>,
+    file_id: RefCell<Option<String>>,
+    drive_status: Cell<Option<u16>>,
+    cancelled: Cell<bool>,
+    protected: Cell<bool>,
+}
+
+impl RequestLog {
+    /// Notes the ID Cloudflare assigned the request (its CF-Ray).
+    pub fn record_request_id(&self, request_id: &str) {
+        *self.request_id.borrow_mut() = Some(request_id.to_string());
+    }
+
+    /// The request's CF-Ray ID, for correlating error reports with logs.
+    pub fn request_id(&self) -> Option<String> {
+        self.request_id.borrow().clone()
+    }
+
+    /// Notes the Drive file the request ended up serving.
+    pub fn record_file(&self, file_id: &str) {
+        *self.file_id.borrow_mut() = Some(file_id.to_string());
+    }
+
+    /// Notes the status of the latest Drive API call.
+    pub fn record_drive_status(&self, status: u16) {
+        self.drive_status.set(Some(status));
+    }
+
+    /// Notes that the client disconnected and the transfer was abandoned.
+    pub fn record_cancelled(&self) {
+        self.cancelled.set(true);
+    }
+
+    /// Notes that the request reached a folder guarded by an `.access` file.
+    pub fn record_protected(&self) {
+        self.protected.set(true);
+    }
+
+    /// Whether the response is for a folder guarded by an `.access` file.
+    pub fn is_protected(&self) -> bool {
+        self.protected.get()
+    }
+
+    /// Writes the log line for a finished request. Server errors are logged
+    /// as errors so they survive `LOG_LEVEL=error`; everything else is info.
+    pub fn emit(&self, level: LogLevel, req: &Request, status: u16, bytes: Option<u64>, started_at: u64) {
+        let line_level = if status >= 500 { LogLevel::Error } else { LogLevel::Info };
+        if line_level > level {
+            return;
+        }
+
+        let line = serde_json::json!({
+            "method": req.method().to_string(),
+            "path": req.path(),
+            "request_id": *self.request_id.borrow(),
+            "status": status,
+            "file_id": *self.file_id.borrow(),
+            "drive_status": self.drive_status.get(),
+            "cancelled": self.cancelled.get(),
+            "protected": self.protected.get(),
+            "bytes": bytes,
+            "latency_ms": Date::now().as_millis().saturating_sub(started_at),
+        });
+        match line_level {
+            LogLevel::Error => console_error!("{}", line),
+            _ => console_log!("{}", line),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::config::Config;
+use crate::drive::{DriveClient, UpstreamError};
+use crate::render::html_escape;
+use crate::roots::Roots;
+use crate::{access, build_query, is_hidden, query_files, visible_files, FOLDER_MIME_TYPE, MAX_FOLDER_DEPTH};
+
+// Caps on a single walk, so a huge or badly shaped tree can't burn through
+// the Drive quota. Anything past them is left out of the manifest.
+const MAX_MANIFEST_FOLDERS: usize = 500;
+const MAX_MANIFEST_FILES: usize = 10_000;
+
+// Versioned so manifests cached before hidden and blocked files were left
+// out aren't served
+pub const CACHE_KEY: &str = "manifest:v2";
+
+/// One file in the manifest: the URL path it's served at and its Drive ID.
+#[derive(Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub id: String,
+}
+
+/// Every file below every root folder that a listing would show, served from
+/// KV when a recent walk is cached there. Both `/sitemap.xml` and
+/// `/manifest.json` are built from these. `refresh` forces a new walk.
+pub async fn entries(auth: &impl DriveClient, config: &Config, roots: &Roots, ctx: &Context, refresh: bool) -> std::result::Result<Vec<ManifestEntry>, UpstreamError> {
+    if !refresh {
+        if let Some(entries) = crate::cached_json(config, CACHE_KEY).await {
+            return Ok(entries);
+        }
+    }
+    let entries = walk(auth, config, roots).await?;
+    crate::store_json(config, ctx, CACHE_KEY.to_string(), &entries, config.manifest_ttl)?;
+    Ok(entries)
+}
+
+// Lists the tree breadth first. Shortcuts are never followed, and each folder
+// is visited at most once, so links between folders can't loop. The manifest
+// is shared by everyone who asks for it, so folders with an `.access` file
+// are left out entirely rather than checked against the request, and so are
+// hidden names and files the extension rules keep out of listings, since
+// there's no `?all=1` to ask for them.
+async fn walk(auth: &impl DriveClient, config: &Config, roots: &Roots) -> std::result::Result<Vec<ManifestEntry>, UpstreamError> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending: VecDeque<(String, String, usize)> = roots
+        .all()
+        .into_iter()
+        .map(|root| (root.folder_id.to_string(), root.base_path(&config.path_prefix), 0))
+        .collect();
+
+    while let Some((folder_id, base_path, depth)) = pending.pop_front() {
+        if !visited.insert(folder_id.clone()) {
+            continue;
+        }
+        if visited.len() > MAX_MANIFEST_FOLDERS {
+            console_warn!("Manifest truncated after {} folders", MAX_MANIFEST_FOLDERS);
+            break;
+        }
+        if access::is_protected(auth, config, &folder_id).await? {
+            continue;
+        }
+
+        let listed = query_files(auth, config, &build_query(config, &folder_id, &[]), Some("name_natural")).await?;
+        for file in visible_files(config, listed).into_iter().filter(|file| !is_hidden(file)) {
+            let path = format!("{}{}", base_path, urlencoding::encode(&file.name));
+            if file.mime_type == FOLDER_MIME_TYPE {
+                if depth + 1 < MAX_FOLDER_DEPTH {
+                    pending.push_back((file.id, format!("{}/", path), depth + 1));
+                }
+                continue;
+            }
+            if entries.len() >= MAX_MANIFEST_FILES {
+                console_warn!("Manifest truncated after {} files", MAX_MANIFEST_FILES);
+                return Ok(entries);
+            }
+            entries.push(ManifestEntry { path, id: file.id });
+        }
+    }
+    Ok(entries)
+}
+
+/// Renders the entries as a sitemap, with absolute URLs under `origin`.
+pub fn sitemap(origin: &str, entries: &[ManifestEntry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for entry in entries {
+        xml.push_str(&format!("  <url><loc>{}{}</loc></url>\n", html_escape(origin), html_escape(&entry.path)));
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::drive::fake::{block_on, FakeDrive};
+
+    fn file(id: &str, name: &str, mime_type: &str) -> serde_json::Value {
+        json!({ "id": id, "name": name, "mimeType": mime_type })
+    }
+
+    #[test]
+    fn leaves_out_what_listings_hide() {
+        let drive = FakeDrive::default()
+            .with_file("root", file("a", "report.pdf", "application/pdf"))
+            .with_file("root", file("b", "setup.exe", "application/octet-stream"))
+            .with_file("root", file("c", ".env", "text/plain"))
+            .with_file("root", file("d", "_template.html", "text/html"))
+            .with_file("root", file("e", "docs", FOLDER_MIME_TYPE))
+            .with_file("root", file("f", "_drafts", FOLDER_MIME_TYPE))
+            .with_file("e", file("g", "guide.pdf", "application/pdf"))
+            .with_file("f", file("h", "draft.pdf", "application/pdf"));
+        let config = Config {
+            list_page_size: 1000,
+            drive_max_attempts: 1,
+            deny_extensions: vec!["exe".to_string()],
+            ..Config::default()
+        };
+        let roots = Roots::Single("root".to_string());
+
+        let entries = block_on(walk(&drive, &config, &roots)).unwrap_or_else(|err| panic!("{}", err));
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["/files/report.pdf", "/files/docs/guide.pdf"]);
+    }
+}
@@ -0,0 +1,109 @@
+// Drive's catch-all type for content it couldn't identify
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+// Drive's usual type for anything that looks like text
+const TEXT_PLAIN: &str = "text/plain";
+
+// Subtitle formats for HTML5 `<track>` elements
+const TEXT_VTT: &str = "text/vtt";
+const SUBRIP: &str = "application/x-subrip";
+
+/// Guesses a MIME type from a file name's extension, for files that Drive
+/// only reports as a generic octet-stream.
+pub fn from_extension(file_name: &str) -> Option<&'static str> {
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let mime_type = match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "mp4" => "video/mp4",
+        "vtt" => TEXT_VTT,
+        "srt" => SUBRIP,
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+/// Picks the Content-Type to serve a file with. A specific type reported by
+/// Drive always wins; the extension is only consulted for octet-stream, and
+/// for subtitles that Drive reports as plain text. Both give way to the
+/// `MIME_TYPES` overrides, which callers check first.
+pub fn content_type<'a>(drive_mime_type: &'a str, file_name: &str) -> &'a str {
+    match drive_mime_type {
+        OCTET_STREAM => from_extension(file_name).unwrap_or(drive_mime_type),
+        TEXT_PLAIN => match from_extension(file_name) {
+            Some(mime_type) if is_text_track(mime_type) => mime_type,
+            _ => drive_mime_type,
+        },
+        _ => drive_mime_type,
+    }
+}
+
+/// Whether a type is a subtitle format, which browsers only load
+/// cross-origin with CORS.
+pub fn is_text_track(content_type: &str) -> bool {
+    content_type == TEXT_VTT || content_type == SUBRIP
+}
+
+/// Whether a type is text underneath, whatever its major type says.
+pub fn is_text(content_type: &str) -> bool {
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    mime_type.starts_with("text/")
+        || matches!(mime_type.as_str(), "application/json" | "application/javascript" | "image/svg+xml")
+}
+
+/// Adds a charset to text-like types that don't already name one, since
+/// browsers otherwise guess and tend to garble UTF-8.
+pub fn with_charset(content_type: &str, charset: &str) -> String {
+    if !is_text(content_type) || content_type.to_ascii_lowercase().contains("charset=") {
+        return content_type.to_string();
+    }
+    format!("{}; charset={}", content_type, charset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_svg_and_javascript_from_octet_stream() {
+        assert_eq!(content_type(OCTET_STREAM, "logo.svg"), "image/svg+xml");
+        assert_eq!(content_type(OCTET_STREAM, "LOGO.SVG"), "image/svg+xml");
+        assert_eq!(content_type(OCTET_STREAM, "app.js"), "text/javascript");
+        assert_eq!(content_type(OCTET_STREAM, "module.mjs"), "text/javascript");
+    }
+
+    #[test]
+    fn keeps_a_specific_type_from_drive() {
+        assert_eq!(content_type("application/javascript", "app.js"), "application/javascript");
+        assert_eq!(content_type("image/png", "logo.svg"), "image/png");
+        assert_eq!(content_type(OCTET_STREAM, "archive.unknown"), OCTET_STREAM);
+        assert_eq!(content_type(OCTET_STREAM, "no-extension"), OCTET_STREAM);
+    }
+
+    #[test]
+    fn adds_a_charset_to_text_types() {
+        assert_eq!(with_charset("text/html", "utf-8"), "text/html; charset=utf-8");
+        assert_eq!(with_charset("application/json", "utf-8"), "application/json; charset=utf-8");
+        assert_eq!(with_charset("image/svg+xml", "utf-8"), "image/svg+xml; charset=utf-8");
+    }
+
+    #[test]
+    fn keeps_an_existing_charset() {
+        assert_eq!(with_charset("text/plain; charset=iso-8859-1", "utf-8"), "text/plain; charset=iso-8859-1");
+        assert_eq!(with_charset("text/plain;Charset=Shift_JIS", "utf-8"), "text/plain;Charset=Shift_JIS");
+    }
+
+    #[test]
+    fn leaves_non_text_types_alone() {
+        for content_type in ["image/png", "application/pdf", "application/octet-stream", "video/mp4"] {
+            assert_eq!(with_charset(content_type, "utf-8"), content_type);
+        }
+    }
+}
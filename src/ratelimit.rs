@@ -0,0 +1,55 @@
+use worker::*;
+
+use crate::config::Config;
+
+/// Counts requests per client IP in fixed windows stored in KV, turning
+/// clients away once they pass `RATE_LIMIT` requests in a window. KV is
+/// eventually consistent, so the limit is approximate, which is plenty for
+/// keeping a runaway client off the Drive quota.
+///
+/// Returns the 429 to send back when the client is over its limit.
+pub async fn check(req: &Request, config: &Config, ctx: &Context) -> Result<Option<Response>> {
+    let (limit, kv) = match (config.rate_limit, &config.kv) {
+        (Some(limit), Some(kv)) => (limit, kv.clone()),
+        _ => return Ok(None),
+    };
+    // Requests that don't come through Cloudflare's edge have no client IP
+    let ip = match req.headers().get("CF-Connecting-IP")? {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+
+    let window = config.rate_limit_window;
+    let now = Date::now().as_millis() / 1000;
+    let window_start = now - now % window;
+    let key = format!("ratelimit:{}:{}", ip, window_start);
+
+    let count = match kv.get(&key).text().await {
+        Ok(count) => count.and_then(|count| count.parse::<u32>().ok()).unwrap_or(0),
+        Err(err) => {
+            // Fail open: a KV hiccup shouldn't take the whole site down
+            console_warn!("Failed to read rate limit for {}: {:?}", ip, err);
+            return Ok(None);
+        }
+    };
+
+    if count >= limit {
+        let headers = Headers::new();
+        headers.set("Retry-After", &(window_start + window - now).to_string())?;
+        return Ok(Some(Response::error("Too many requests", 429)?.with_headers(headers)));
+    }
+
+    // The window's key only needs to outlive the window, but KV won't expire
+    // anything sooner than a minute
+    let ttl = window.max(60);
+    ctx.wait_until(async move {
+        let result = match kv.put(&key, (count + 1).to_string()) {
+            Ok(put) => put.expiration_ttl(ttl).execute().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            console_warn!("Failed to record rate limit for {}: {:?}", ip, err);
+        }
+    });
+    Ok(None)
+}
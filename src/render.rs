@@ -0,0 +1,366 @@
+use crate::config::Config;
+use crate::{decode_path_segment, short_slug, DriveFile, FOLDER_MIME_TYPE, GOOGLE_APPS_MIME_PREFIX};
+
+// Built-in page shell. `{{title}}` is the page heading, `{{content}}` the
+// rendered markup that goes below it and `{{theme}}` the class picking the
+// colour scheme. The dark palette is spelled out twice since a media query
+// can't share a rule with the forced `theme-dark`.
+const DEFAULT_PAGE_TEMPLATE: &str = r#"
+<!DOCTYPE html>
+<html class="{{theme}}">
+<head>
+    <title>Drive Files</title>
+    <style>
+        :root { color-scheme: light; --text: #222; --background: #fff; --muted: #666; --faint: #bbb; --border: #ddd; --heading: #444; --link: #1976d2; --error: #b00020; }
+        :root.theme-dark { color-scheme: dark; --text: #e4e4e4; --background: #121212; --muted: #9e9e9e; --faint: #555; --border: #333; --heading: #c8c8c8; --link: #64b5f6; --error: #ff7081; }
+        @media (prefers-color-scheme: dark) {
+            :root.theme-auto { color-scheme: dark; --text: #e4e4e4; --background: #121212; --muted: #9e9e9e; --faint: #555; --border: #333; --heading: #c8c8c8; --link: #64b5f6; --error: #ff7081; }
+        }
+        body { font-family: Arial, sans-serif; margin: 40px; color: var(--text); background: var(--background); }
+        .file { margin: 10px 0; padding: 10px; border: 1px solid var(--border); border-radius: 5px; }
+        .file-name { font-weight: bold; }
+        .file-type { color: var(--muted); font-size: 0.9em; }
+        .file-size { color: var(--muted); font-size: 0.9em; }
+        .file-link { color: var(--muted); font-size: 0.9em; }
+        .empty { color: var(--muted); }
+        .error { color: var(--error); }
+        .breadcrumbs { margin-bottom: 20px; color: var(--muted); }
+        .summary { margin-bottom: 20px; color: var(--muted); }
+        .warning { margin-bottom: 20px; color: var(--error); }
+        .group { font-size: 1.1em; margin: 20px 0 10px; color: var(--heading); }
+        .preview { margin: 20px 0; padding: 10px; border: 1px solid var(--border); border-radius: 5px; }
+        .preview pre { margin: 10px 0 0; white-space: pre-wrap; overflow-wrap: anywhere; }
+        .pagination { margin: 20px 0; color: var(--muted); }
+        .pagination .disabled { color: var(--faint); }
+        a { text-decoration: none; color: var(--link); }
+        a:hover { text-decoration: underline; }
+    </style>
+</head>
+<body>
+    <h1>{{title}}</h1>
+{{content}}</body></html>"#;
+
+// Built-in markup for a single listing entry
+const DEFAULT_ROW_TEMPLATE: &str = r#"
+    <div class="file">
+        <div class="file-name">
+            <a href="{{link}}">{{name}}</a>
+        </div>
+        <div class="file-type">{{type}}</div>
+        <div class="file-size">{{size}}</div>
+        {{short_link}}
+    </div>
+"#;
+
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Substitutes `{{key}}` placeholders in a single pass, so values that happen
+// to contain placeholder syntax are left alone. Unknown placeholders are kept
+// verbatim.
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let key = after[..end].trim();
+        match values.iter().find(|(name, _)| *name == key) {
+            Some((_, value)) => filled.push_str(value),
+            None => filled.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// The colour scheme of a generated page, as picked with `?theme=`.
+#[derive(Clone, Copy)]
+pub enum Theme {
+    /// Follows the system setting
+    Auto,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    /// Reads a `?theme=` value, falling back to `Auto` for anything unknown.
+    pub fn from_param(value: Option<&str>) -> Self {
+        match value.map(str::to_ascii_lowercase).as_deref() {
+            Some("light") => Theme::Light,
+            Some("dark") => Theme::Dark,
+            _ => Theme::Auto,
+        }
+    }
+
+    /// The class the page shell is given for the theme.
+    pub fn class(self) -> &'static str {
+        match self {
+            Theme::Auto => "theme-auto",
+            Theme::Light => "theme-light",
+            Theme::Dark => "theme-dark",
+        }
+    }
+}
+
+/// Wraps already-rendered body markup in the page shell shared by every
+/// generated page, using `LISTING_TEMPLATE` when one is configured.
+pub fn render_page(config: &Config, theme: Theme, heading: &str, body: &str) -> String {
+    let template = config.listing_template.as_deref().unwrap_or(DEFAULT_PAGE_TEMPLATE);
+    fill_template(template, &[("title", &html_escape(heading)), ("content", body), ("theme", theme.class())])
+}
+
+/// Renders the page shown for an error response, headed by its status.
+pub fn render_error_page(config: &Config, theme: Theme, status: u16, message: &str) -> String {
+    let reason = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Error");
+    let body = format!("    <p class=\"error\">{}</p>\n", html_escape(message));
+    render_page(config, theme, &format!("{} {}", status, reason), &body)
+}
+
+/// Formats a byte count for humans using binary (1024-based) units.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Renders a "Home / sub / subsub" trail for a slash-terminated listing path
+/// below `root_path`, with every ancestor linking back to its own listing.
+pub fn render_breadcrumbs(root_path: &str, path: &str) -> String {
+    let mut crumbs = vec![format!(r#"<a href="{}">Home</a>"#, html_escape(root_path))];
+    let mut href = String::from(root_path);
+    for segment in path.strip_prefix(root_path).unwrap_or_default().split('/').filter(|segment| !segment.is_empty()) {
+        href.push_str(segment);
+        href.push('/');
+        let name = decode_path_segment(segment).unwrap_or_else(|| segment.to_string());
+        crumbs.push(format!(r#"<a href="{}">{}</a>"#, html_escape(&href), html_escape(&name)));
+    }
+    format!("    <div class=\"breadcrumbs\">{}</div>\n", crumbs.join(" / "))
+}
+
+/// Renders the "12 files, 3.4 MB total" line for a listing. Folders aren't
+/// counted, and Google-native files count as files but have no size, so
+/// they're noted separately rather than added to the total.
+pub fn render_summary(files: &[DriveFile]) -> String {
+    let mut count = 0;
+    let mut native = 0;
+    let mut total = 0u64;
+    for file in files.iter().filter(|file| file.mime_type != FOLDER_MIME_TYPE) {
+        count += 1;
+        match file.size.as_deref().and_then(|size| size.parse::<u64>().ok()) {
+            Some(bytes) => total += bytes,
+            None if file.mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX) => native += 1,
+            None => {}
+        }
+    }
+    let mut summary = format!("{} {}, {} total", count, if count == 1 { "file" } else { "files" }, format_bytes(total));
+    if native > 0 {
+        summary.push_str(&format!(" ({} Google {} not included)", native, if native == 1 { "file" } else { "files" }));
+    }
+    format!("    <div class=\"summary\">{}</div>\n", summary)
+}
+
+/// Renders the panel `?preview=` shows above a listing's rows, with `text`
+/// as the file's content or, without it, a note that it can't be previewed.
+pub fn render_preview(name: &str, text: Option<&str>) -> String {
+    let content = match text {
+        Some(text) => format!("<pre>{}</pre>", html_escape(text)),
+        None => String::from(r#"<p class="empty">This file can't be previewed.</p>"#),
+    };
+    format!(
+        "    <div class=\"preview\">\n        <div class=\"file-name\">{}</div>\n        {}\n    </div>\n",
+        html_escape(name), content
+    )
+}
+
+/// Renders the warning shown when Drive reports that it couldn't search
+/// everywhere, so a listing or search may be missing files.
+pub fn render_incomplete_notice() -> String {
+    String::from("    <div class=\"warning\">Google Drive couldn't search every location, so some files may be missing.</div>\n")
+}
+
+/// Renders the "Showing 1–50" range for a page of a listing, along with its
+/// Prev and Next links. A missing link is shown disabled.
+pub fn render_pagination(offset: usize, count: usize, prev_href: Option<&str>, next_href: Option<&str>) -> String {
+    let range = if count == 0 {
+        String::from("No files on this page")
+    } else {
+        format!("Showing {}–{}", offset + 1, offset + count)
+    };
+    let link = |label: &str, href: Option<&str>| match href {
+        Some(href) => format!(r#"<a href="{}">{}</a>"#, html_escape(href), label),
+        None => format!(r#"<span class="disabled">{}</span>"#, label),
+    };
+    format!(
+        "    <div class=\"pagination\">{} · {} · {}</div>\n",
+        link("Prev", prev_href), range, link("Next", next_href)
+    )
+}
+
+/// Renders a listing as plain text, one name per line, with a trailing slash
+/// marking folders.
+pub fn render_text_listing(files: &[DriveFile]) -> String {
+    let mut text = String::new();
+    for file in files {
+        text.push_str(&file.name);
+        if file.mime_type == FOLDER_MIME_TYPE {
+            text.push('/');
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Renders one row per file, linking each relative to `base_path`, using
+/// `LISTING_ROW_TEMPLATE` when one is configured. Files that share a name
+/// link with `?id=` so each one stays reachable. With `short_links`, files
+/// also get their `/s/` link, which the caller has to have stored.
+pub fn render_file_rows(config: &Config, files: &[DriveFile], base_path: &str, short_links: bool) -> String {
+    files.iter().map(|file| render_file_row(config, file, files, base_path, short_links)).collect()
+}
+
+// The broad kinds of file a listing can be grouped into with `?group=type`,
+// in the order the groups are shown
+const FILE_GROUPS: [&str; 5] = ["Folders", "Documents", "Images", "Videos", "Other"];
+
+// Sorts a MIME type into one of `FILE_GROUPS`
+fn file_group(mime_type: &str) -> &'static str {
+    const DOCUMENT_TYPES: [&str; 5] = [
+        "application/pdf",
+        "application/msword",
+        "application/rtf",
+        "application/vnd.openxmlformats-officedocument.",
+        "application/vnd.oasis.opendocument.",
+    ];
+    if mime_type == FOLDER_MIME_TYPE {
+        "Folders"
+    } else if mime_type.starts_with("image/") {
+        "Images"
+    } else if mime_type.starts_with("video/") {
+        "Videos"
+    } else if mime_type.starts_with("text/")
+        || mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX)
+        || DOCUMENT_TYPES.iter().any(|prefix| mime_type.starts_with(prefix))
+    {
+        "Documents"
+    } else {
+        "Other"
+    }
+}
+
+/// Like `render_file_rows`, but with the files split under a heading for
+/// each kind (folders, documents, images, videos, other), keeping their
+/// order within each group. Empty groups are left out.
+pub fn render_grouped_file_rows(config: &Config, files: &[DriveFile], base_path: &str, short_links: bool) -> String {
+    let mut html = String::new();
+    for group in FILE_GROUPS {
+        let rows: String = files
+            .iter()
+            .filter(|file| file_group(&file.mime_type) == group)
+            .map(|file| render_file_row(config, file, files, base_path, short_links))
+            .collect();
+        if !rows.is_empty() {
+            html.push_str(&format!("    <h2 class=\"group\">{}</h2>\n{}", group, rows));
+        }
+    }
+    html
+}
+
+// Renders a single row. `files` is the whole listing, which decides whether
+// the name is shared and needs `?id=` to tell it apart.
+fn render_file_row(config: &Config, file: &DriveFile, files: &[DriveFile], base_path: &str, short_links: bool) -> String {
+    let template = config.listing_row_template.as_deref().unwrap_or(DEFAULT_ROW_TEMPLATE);
+    let encoded_name = urlencoding::encode(&file.name);
+    let (href, display_name) = if file.mime_type == FOLDER_MIME_TYPE {
+        (format!("{}{}/", base_path, encoded_name), format!("{}/", file.name))
+    } else if files.iter().filter(|other| other.name == file.name && other.mime_type != FOLDER_MIME_TYPE).count() > 1 {
+        (format!("{}{}?id={}", base_path, encoded_name, urlencoding::encode(&file.id)), file.name.clone())
+    } else {
+        (format!("{}{}", base_path, encoded_name), file.name.clone())
+    };
+    // Google-native files and folders have no size of their own
+    let size = match file.size.as_deref().and_then(|size| size.parse::<u64>().ok()) {
+        Some(bytes) => format_bytes(bytes),
+        None => String::from("—"),
+    };
+    let short_link = if short_links && file.mime_type != FOLDER_MIME_TYPE {
+        let short_href = html_escape(&format!("{}/s/{}", config.path_prefix, short_slug(&file.id)));
+        format!(r#"<div class="file-link"><a href="{0}">{0}</a></div>"#, short_href)
+    } else {
+        String::new()
+    };
+    // Names and types come straight from Drive, so nothing reaches the
+    // markup unescaped
+    fill_template(template, &[
+        ("link", &html_escape(&href)),
+        ("name", &html_escape(&display_name)),
+        ("type", &html_escape(&file.mime_type)),
+        ("size", &size),
+        ("short_link", &short_link),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup_in_listed_file_names() {
+        let file: DriveFile = serde_json::from_value(serde_json::json!({
+            "id": "abc",
+            "name": "<b>\"Tom & Jerry\"</b>.txt",
+            "mimeType": "text/plain",
+        }))
+        .unwrap();
+        let html = render_file_rows(&Config::default(), &[file], "/files/", false);
+        assert!(html.contains("&lt;b&gt;&quot;Tom &amp; Jerry&quot;&lt;/b&gt;.txt"), "{}", html);
+        assert!(!html.contains("<b>"), "{}", html);
+        assert!(!html.contains("\"Tom"), "{}", html);
+        // The link is percent-encoded, so it can't break out of its attribute
+        assert!(html.contains(r#"href="/files/%3Cb%3E%22Tom%20%26%20Jerry%22%3C%2Fb%3E.txt""#), "{}", html);
+    }
+
+    #[test]
+    fn formats_bytes_at_unit_boundaries() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KB");
+        // The unit is picked before rounding, so just under one shows as a
+        // full 1024 of the unit below
+        assert_eq!(format_bytes(1024 * 1024 - 1), "1024.0 KB");
+        assert_eq!(format_bytes(1024 * 1024), "1.0 MB");
+        assert_eq!(format_bytes(1536 * 1024), "1.5 MB");
+        assert_eq!(format_bytes(1024 * 1024 * 1024 - 1), "1024.0 MB");
+        assert_eq!(format_bytes(1024 * 1024 * 1024), "1.0 GB");
+        // Nothing past petabytes, however big
+        assert_eq!(format_bytes(u64::MAX), "16384.0 PB");
+    }
+}
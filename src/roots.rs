@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use worker::*;
+
+// Path segment the only folder is served under when a bare ID is configured
+const SINGLE_ROOT_PREFIX: &str = "files";
+
+/// The Drive folders the worker serves, as configured by `GOOGLE_DRIVE_FOLDER_ID`.
+pub enum Roots {
+    /// A bare folder ID, served under `/files/`
+    Single(String),
+    /// A JSON object mapping top-level path segments to folder IDs
+    Prefixed(HashMap<String, String>),
+}
+
+/// The root folder a request path resolved to, and the path left below it.
+pub struct Root<'a> {
+    pub prefix: &'a str,
+    pub folder_id: &'a str,
+    pub rest: &'a str,
+}
+
+impl Root<'_> {
+    /// The slash-terminated URL path this root's listing lives at, below the
+    /// worker's own `PATH_PREFIX`.
+    pub fn base_path(&self, path_prefix: &str) -> String {
+        format!("{}/{}/", path_prefix, self.prefix)
+    }
+}
+
+impl Roots {
+    /// Anything that looks like a JSON object is read as a prefix map; any
+    /// other value is taken to be a single folder ID.
+    pub fn parse(secret: &str) -> Result<Self> {
+        let secret = secret.trim();
+        if !secret.starts_with('{') {
+            return Ok(Roots::Single(secret.to_string()));
+        }
+        let prefixes: HashMap<String, String> = serde_json::from_str(secret)
+            .map_err(|err| Error::from(format!("Invalid GOOGLE_DRIVE_FOLDER_ID map: {}", err)))?;
+        Ok(Roots::Prefixed(prefixes))
+    }
+
+    /// Every configured root.
+    pub fn all(&self) -> Vec<Root<'_>> {
+        match self {
+            Roots::Single(folder_id) => vec![Root { prefix: SINGLE_ROOT_PREFIX, folder_id, rest: "" }],
+            Roots::Prefixed(prefixes) => prefixes
+                .iter()
+                .map(|(prefix, folder_id)| Root { prefix, folder_id, rest: "" })
+                .collect(),
+        }
+    }
+
+    /// Every configured root folder ID.
+    pub fn folder_ids(&self) -> Vec<&str> {
+        match self {
+            Roots::Single(folder_id) => vec![folder_id.as_str()],
+            Roots::Prefixed(prefixes) => prefixes.values().map(String::as_str).collect(),
+        }
+    }
+
+    /// Picks the root for a top-level file path such as `/files/a/b.png` or,
+    /// with a prefix map, `/docs/a/b.png`.
+    pub fn for_files_path<'a>(&'a self, path: &'a str) -> Option<Root<'a>> {
+        match self {
+            Roots::Single(folder_id) => path.strip_prefix("/files/").map(|rest| Root {
+                prefix: SINGLE_ROOT_PREFIX,
+                folder_id,
+                rest,
+            }),
+            Roots::Prefixed(_) => self.for_nested_path(path.strip_prefix('/')?),
+        }
+    }
+
+    /// Picks the root for the part of a path below a route like `/thumb/`.
+    /// With a prefix map its first segment names the root; a single root
+    /// takes the whole path as is.
+    pub fn for_nested_path<'a>(&'a self, path: &'a str) -> Option<Root<'a>> {
+        match self {
+            Roots::Single(folder_id) => Some(Root { prefix: SINGLE_ROOT_PREFIX, folder_id, rest: path }),
+            Roots::Prefixed(prefixes) => {
+                let (prefix, rest) = path.split_once('/')?;
+                let (prefix, folder_id) = prefixes.get_key_value(prefix)?;
+                Some(Root { prefix, folder_id, rest })
+            }
+        }
+    }
+
+    /// Picks a root by name, as given in a query parameter. A single root
+    /// ignores the name entirely.
+    pub fn by_name<'a>(&'a self, name: Option<&str>) -> Option<Root<'a>> {
+        match self {
+            Roots::Single(folder_id) => Some(Root { prefix: SINGLE_ROOT_PREFIX, folder_id, rest: "" }),
+            Roots::Prefixed(prefixes) => {
+                let (prefix, folder_id) = prefixes.get_key_value(name?)?;
+                Some(Root { prefix, folder_id, rest: "" })
+            }
+        }
+    }
+}
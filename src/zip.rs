@@ -0,0 +1,186 @@
+// A minimal streaming ZIP writer. Entries are stored uncompressed, which is
+// what lets each file pass straight through: the CRC and sizes follow the
+// data in a descriptor instead of having to be known up front. There's no
+// ZIP64 support, so callers have to keep archives under 4 GB.
+
+use chrono::{DateTime, Datelike, Timelike};
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+// 2.0, the first version with data descriptors
+const VERSION: u16 = 20;
+
+// Sizes and CRC come after the data (bit 3); names are UTF-8 (bit 11)
+const FLAGS: u16 = 0x0808;
+
+const METHOD_STORED: u16 = 0;
+
+/// Largest archive the writer can produce without ZIP64.
+pub const MAX_ARCHIVE_SIZE: u64 = u32::MAX as u64;
+
+/// Bytes the archive adds around an entry's data, for checking sizes before
+/// starting.
+pub fn entry_overhead(name: &str) -> u64 {
+    // Local header, data descriptor and central directory header
+    (30 + 16 + 46 + 2 * name.len()) as u64
+}
+
+struct Entry {
+    name: String,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Produces an archive as a sequence of byte chunks: for each entry, a
+/// header, its data, then a descriptor, and finally the central directory.
+#[derive(Default)]
+pub struct ZipWriter {
+    entries: Vec<Entry>,
+    offset: u32,
+    crc: u32,
+    size: u32,
+}
+
+impl ZipWriter {
+    /// Starts a new entry, returning its local header. `modified_time` is
+    /// the file's RFC 3339 modification time, if known.
+    pub fn start_entry(&mut self, name: &str, modified_time: Option<&str>) -> Vec<u8> {
+        let (time, date) = modified_time.map_or((0, 0x21), dos_date_time);
+        let mut header = Vec::with_capacity(30 + name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        // CRC and sizes are left zero and given in the data descriptor
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+
+        self.entries.push(Entry { name: name.to_string(), time, date, crc: 0, size: 0, offset: self.offset });
+        self.crc = !0;
+        self.size = 0;
+        self.offset = self.offset.wrapping_add(header.len() as u32);
+        header
+    }
+
+    /// Accounts for a chunk of the current entry's data, which the caller
+    /// passes on unchanged.
+    pub fn write(&mut self, chunk: &[u8]) {
+        self.crc = crc32_update(self.crc, chunk);
+        self.size = self.size.wrapping_add(chunk.len() as u32);
+        self.offset = self.offset.wrapping_add(chunk.len() as u32);
+    }
+
+    /// Ends the current entry, returning its data descriptor.
+    pub fn finish_entry(&mut self) -> Vec<u8> {
+        let crc = !self.crc;
+        let size = self.size;
+        if let Some(entry) = self.entries.last_mut() {
+            entry.crc = crc;
+            entry.size = size;
+        }
+
+        let mut descriptor = Vec::with_capacity(16);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, crc);
+        put_u32(&mut descriptor, size);
+        put_u32(&mut descriptor, size);
+        self.offset = self.offset.wrapping_add(descriptor.len() as u32);
+        descriptor
+    }
+
+    /// Returns the central directory that closes the archive.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let directory_offset = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            put_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, FLAGS);
+            put_u16(&mut directory, METHOD_STORED);
+            put_u16(&mut directory, entry.time);
+            put_u16(&mut directory, entry.date);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, entry.size);
+            put_u32(&mut directory, entry.size);
+            put_u16(&mut directory, entry.name.len() as u16);
+            // Extra field, comment, disk number, internal and external attributes
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u32(&mut directory, 0);
+            put_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let directory_size = directory.len() as u32;
+
+        put_u32(&mut directory, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, self.entries.len() as u16);
+        put_u16(&mut directory, self.entries.len() as u16);
+        put_u32(&mut directory, directory_size);
+        put_u32(&mut directory, directory_offset);
+        put_u16(&mut directory, 0);
+        directory
+    }
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+// Converts an RFC 3339 time to the packed MS-DOS time and date ZIP uses,
+// which can't represent anything before 1980
+fn dos_date_time(modified_time: &str) -> (u16, u16) {
+    match DateTime::parse_from_rfc3339(modified_time) {
+        Ok(modified) if modified.year() >= 1980 => {
+            let time = (modified.hour() << 11) | (modified.minute() << 5) | (modified.second() / 2);
+            let date = (((modified.year() - 1980) as u32) << 9) | (modified.month() << 5) | modified.day();
+            (time as u16, date as u16)
+        }
+        _ => (0, 0x21),
+    }
+}
+
+// Lookup table for the reflected CRC-32 polynomial ZIP uses
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+